@@ -0,0 +1,390 @@
+//! AppStream data for generated runtimes and apps
+//!
+//! `flatpak build-update-repo` composes the repo's appstream branch from
+//! `files/share/app-info` of every ref: a catalog at `xmls/<id>.xml.gz` and
+//! icons at `icons/flatpak/<size>/<id>.png`. Runtimes get a synthesized
+//! component, apps take the metainfo shipped in the image or one made up
+//! from its desktop file.
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use log::{debug, info, warn};
+use quick_xml::events::{BytesDecl, BytesStart, BytesText, Event};
+use quick_xml::{Reader, Writer};
+use std::fs::{copy, create_dir_all, read_dir, read_to_string, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::flatpak::Metadata;
+use crate::Result;
+
+const METAINFO_DIRS: &[&str] = &["usr/share/metainfo", "usr/share/appdata"];
+const APPLICATIONS_DIR: &str = "usr/share/applications";
+pub const ICON_THEME_DIR: &str = "usr/share/icons/hicolor";
+/// Icon sizes flatpak picks up, (directory, pixels)
+const ICON_SIZES: &[(&str, &str)] = &[("64x64", "64"), ("128x128", "128")];
+/// Component types that describe an application
+const APP_TYPES: &[&str] = &["desktop", "desktop-application", "console-application"];
+pub const DESKTOP_GROUP: &str = "Desktop Entry";
+
+/// Directories of the image holding what appstream data, exported desktop
+/// files and icons are made of
+pub const SOURCE_DIRS: &[&str] = &["usr/share"];
+
+/// Description of a runtime for software centers
+#[derive(Clone, Debug)]
+pub struct Info {
+    pub name: String,
+    pub summary: String,
+}
+
+type XmlWriter = Writer<Vec<u8>>;
+type XmlResult = std::result::Result<(), quick_xml::Error>;
+
+fn text_element(w: &mut XmlWriter, name: &str, text: &str) -> XmlResult {
+    w.create_element(name)
+        .write_text_content(BytesText::new(text))?;
+    Ok(())
+}
+
+/// Cached icons and the bundle, which the catalog has to carry for flatpak
+fn write_flatpak_elements(w: &mut XmlWriter, id: &str, icons: &[&str], bundle: &str) -> XmlResult {
+    for size in icons {
+        w.create_element("icon")
+            .with_attributes([("type", "cached"), ("width", size), ("height", size)])
+            .write_text_content(BytesText::new(&format!("{id}.png")))?;
+    }
+    w.create_element("bundle")
+        .with_attribute(("type", "flatpak"))
+        .write_text_content(BytesText::new(bundle))?;
+    Ok(())
+}
+
+/// Gzipped catalog holding a single component, `body` writing its content
+fn write_catalog<F>(files: &Path, id: &str, body: F) -> Result<()>
+where
+    F: FnOnce(&mut XmlWriter) -> XmlResult,
+{
+    let mut w = Writer::new_with_indent(Vec::new(), b' ', 2);
+    w.write_event(Event::Decl(BytesDecl::new("1.0", Some("UTF-8"), None)))?;
+    w.create_element("components")
+        .with_attributes([("version", "0.8"), ("origin", "flatpak")])
+        .write_inner_content(body)?;
+
+    let dir = files.join("share/app-info/xmls");
+    create_dir_all(&dir)?;
+    let mut out = GzEncoder::new(File::create(dir.join(format!("{id}.xml.gz")))?, Compression::default());
+    out.write_all(&w.into_inner())?;
+    out.finish()?;
+    Ok(())
+}
+
+fn catalog_exists(files: &Path, id: &str) -> bool {
+    files.join(format!("share/app-info/xmls/{id}.xml.gz")).exists()
+}
+
+/// Synthesize the catalog of runtime tree `files`
+pub fn write_runtime(files: &Path, id: &str, info: &Info, bundle: &str) -> Result<()> {
+    info!("Writing appstream data for {id}");
+    write_catalog(files, id, |w| {
+        w.create_element("component")
+            .with_attribute(("type", "runtime"))
+            .write_inner_content(|w| {
+                text_element(w, "id", id)?;
+                text_element(w, "metadata_license", "CC0-1.0")?;
+                text_element(w, "name", &info.name)?;
+                text_element(w, "summary", &info.summary)?;
+                write_flatpak_elements(w, id, &[], bundle)
+            })?;
+        Ok(())
+    })
+}
+
+/// Sorted entries of `dir` with extension `ext`
+fn list(dir: &Path, ext: &str) -> Result<Vec<PathBuf>> {
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut ret = Vec::new();
+    for entry in read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|e| e == ext) {
+            ret.push(path);
+        }
+    }
+    ret.sort();
+    Ok(ret)
+}
+
+/// Desktop files of the image that show up in menus, by file name
+pub fn desktop_files(image: &Path) -> Result<Vec<(String, Metadata)>> {
+    let mut ret = Vec::new();
+    for path in list(&image.join(APPLICATIONS_DIR), "desktop")? {
+        let entry = match Metadata::parse(&read_to_string(&path)?) {
+            Ok(entry) => entry,
+            Err(e) => {
+                warn!("Ignoring {}: {e}", path.display());
+                continue;
+            }
+        };
+        if entry.get(DESKTOP_GROUP, "Type") != Some("Application")
+            || entry.get(DESKTOP_GROUP, "NoDisplay") == Some("true")
+        {
+            continue;
+        }
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        ret.push((name, entry));
+    }
+    Ok(ret)
+}
+
+/// What the image's metainfo says about the application
+struct Metainfo {
+    path: PathBuf,
+    stock_icon: Option<String>,
+    launchable: Option<String>,
+}
+
+fn attribute(e: &BytesStart, name: &str) -> Option<String> {
+    e.try_get_attribute(name)
+        .ok()
+        .flatten()
+        .and_then(|a| a.unescape_value().ok().map(|v| v.into_owned()))
+}
+
+/// First metainfo file of the image describing an application
+fn find_metainfo(image: &Path) -> Result<Option<Metainfo>> {
+    for dir in METAINFO_DIRS {
+        for path in list(&image.join(dir), "xml")? {
+            let content = read_to_string(&path)?;
+            let mut reader = Reader::from_str(&content);
+            reader.trim_text(true);
+
+            let mut ret = Metainfo {
+                path: path.clone(),
+                stock_icon: None,
+                launchable: None,
+            };
+            let mut depth = 0;
+            // element at depth 2 whose text we're after
+            let mut capture: Option<&mut Option<String>> = None;
+            let mut is_app = false;
+            loop {
+                match reader.read_event() {
+                    Err(e) => {
+                        warn!("Ignoring {}: {e}", path.display());
+                        break;
+                    }
+                    Ok(Event::Eof) => break,
+                    Ok(Event::Start(e)) => {
+                        depth += 1;
+                        if depth == 1 {
+                            is_app = e.name().as_ref() == b"component"
+                                && attribute(&e, "type").is_some_and(|t| APP_TYPES.contains(&t.as_str()));
+                        } else if depth == 2 {
+                            capture = match e.name().as_ref() {
+                                b"icon" if attribute(&e, "type").as_deref() == Some("stock") => {
+                                    Some(&mut ret.stock_icon)
+                                }
+                                b"launchable" if attribute(&e, "type").as_deref() == Some("desktop-id") => {
+                                    Some(&mut ret.launchable)
+                                }
+                                _ => None,
+                            };
+                        }
+                    }
+                    Ok(Event::End(_)) => {
+                        depth -= 1;
+                        capture = None;
+                    }
+                    Ok(Event::Text(t)) => {
+                        if let Some(slot) = capture.take() {
+                            if slot.is_none() {
+                                *slot = t.unescape().ok().map(|t| t.into_owned());
+                            }
+                        }
+                    }
+                    _ => (),
+                }
+            }
+
+            if is_app {
+                return Ok(Some(ret));
+            }
+            debug!("{} doesn't describe an application", path.display());
+        }
+    }
+    Ok(None)
+}
+
+/// Copy the theme icons named `icon` to the app's cached icons, returning
+/// the sizes found
+fn copy_icons(image: &Path, files: &Path, id: &str, icon: &str) -> Result<Vec<&'static str>> {
+    let mut ret = Vec::new();
+    for (dir, size) in ICON_SIZES {
+        let src = image.join(ICON_THEME_DIR).join(dir).join("apps").join(format!("{icon}.png"));
+        if !src.is_file() {
+            continue;
+        }
+        let dest = files.join("share/app-info/icons/flatpak").join(dir);
+        create_dir_all(&dest)?;
+        copy(&src, dest.join(format!("{id}.png")))?;
+        ret.push(*size);
+    }
+    if ret.is_empty() {
+        warn!("No {icon} icon of a usable size in image");
+    }
+    Ok(ret)
+}
+
+/// Name desktop file `file` got when exported, see `export::export_app`
+fn exported_name<'a>(renamed: &'a [(String, String)], file: &'a str) -> &'a str {
+    renamed
+        .iter()
+        .find(|(from, _)| from == file)
+        .map(|(_, to)| to.as_str())
+        .unwrap_or(file)
+}
+
+/// Copy the component of `metainfo` with its id replaced by `id`, desktop
+/// launchables by their `renamed` name and the flatpak specific elements
+/// added
+fn rewrite_metainfo(
+    w: &mut XmlWriter,
+    metainfo: &Path,
+    id: &str,
+    icons: &[&str],
+    bundle: &str,
+    renamed: &[(String, String)],
+) -> Result<()> {
+    let content = read_to_string(metainfo)?;
+    // whitespace is kept, descriptions have mixed content
+    let mut reader = Reader::from_str(&content);
+
+    let mut depth = 0;
+    // depth of an element being dropped
+    let mut skip: Option<usize> = None;
+    let mut in_launchable = false;
+    loop {
+        let event = reader.read_event()?;
+        match &event {
+            Event::Eof => break,
+            Event::Text(_) if depth == 0 => continue,
+            Event::Decl(_) | Event::DocType(_) | Event::PI(_) | Event::Comment(_) => continue,
+            Event::Start(e) => {
+                depth += 1;
+                if skip.is_none() && depth == 2 {
+                    match e.name().as_ref() {
+                        b"id" => {
+                            text_element(w, "id", id)?;
+                            skip = Some(depth);
+                        }
+                        b"icon" | b"bundle" => skip = Some(depth),
+                        b"launchable" => in_launchable = attribute(e, "type").as_deref() == Some("desktop-id"),
+                        _ => (),
+                    }
+                }
+            }
+            Event::Empty(e) if depth == 1 && matches!(e.name().as_ref(), b"icon" | b"bundle") => continue,
+            Event::Text(e) if in_launchable => {
+                let file = e.unescape()?;
+                w.write_event(Event::Text(BytesText::new(exported_name(renamed, file.trim()))))?;
+                continue;
+            }
+            Event::End(_) => {
+                in_launchable = false;
+                depth -= 1;
+                if skip == Some(depth + 1) {
+                    skip = None;
+                    continue;
+                }
+                if depth == 0 {
+                    write_flatpak_elements(w, id, icons, bundle)?;
+                }
+            }
+            _ => (),
+        }
+        if skip.is_none() {
+            w.write_event(event)?;
+        }
+    }
+    Ok(())
+}
+
+/// Component made up from a desktop file
+fn synthesize(w: &mut XmlWriter, id: &str, file: &str, entry: &Metadata, icons: &[&str], bundle: &str) -> XmlResult {
+    let name = entry.get(DESKTOP_GROUP, "Name").unwrap_or(id);
+    let summary = entry
+        .get(DESKTOP_GROUP, "Comment")
+        .or(entry.get(DESKTOP_GROUP, "GenericName"))
+        .unwrap_or(name);
+
+    w.create_element("component")
+        .with_attribute(("type", "desktop-application"))
+        .write_inner_content(|w| {
+            text_element(w, "id", id)?;
+            text_element(w, "metadata_license", "CC0-1.0")?;
+            text_element(w, "name", name)?;
+            text_element(w, "summary", summary)?;
+            w.create_element("launchable")
+                .with_attribute(("type", "desktop-id"))
+                .write_text_content(BytesText::new(file))?;
+            if let Some(categories) = entry.get(DESKTOP_GROUP, "Categories") {
+                w.create_element("categories").write_inner_content(|w| {
+                    for c in categories.split(';').filter(|c| !c.is_empty()) {
+                        text_element(w, "category", c)?;
+                    }
+                    Ok(())
+                })?;
+            }
+            write_flatpak_elements(w, id, icons, bundle)
+        })?;
+    Ok(())
+}
+
+/// Write the catalog of app tree `files` from image content checked out at
+/// `image`, whose desktop files were exported under their `renamed` name.
+/// Apps that ship appstream data of their own are left alone.
+pub fn write_app(image: &Path, files: &Path, id: &str, bundle: &str, renamed: &[(String, String)]) -> Result<()> {
+    if catalog_exists(files, id) {
+        debug!("{id} already has appstream data");
+        return Ok(());
+    }
+
+    let desktops = desktop_files(image)?;
+    let metainfo = find_metainfo(image)?;
+
+    let desktop = metainfo
+        .as_ref()
+        .and_then(|m| m.launchable.as_ref())
+        .and_then(|l| desktops.iter().find(|(name, _)| name == l))
+        .or(desktops.first());
+    let icon = metainfo
+        .as_ref()
+        .and_then(|m| m.stock_icon.as_deref())
+        .or(desktop.and_then(|(_, entry)| entry.get(DESKTOP_GROUP, "Icon")));
+    let icons = match icon {
+        // absolute paths are likely not in a size we can use
+        Some(icon) if !icon.starts_with('/') => copy_icons(image, files, id, icon)?,
+        _ => Vec::new(),
+    };
+
+    info!("Writing appstream data for {id}");
+    match (&metainfo, desktop) {
+        (Some(metainfo), _) => {
+            debug!("Using {}", metainfo.path.display());
+            let mut w = Writer::new(Vec::new());
+            rewrite_metainfo(&mut w, &metainfo.path, id, &icons, bundle, renamed)?;
+            let component = w.into_inner();
+            write_catalog(files, id, |w| w.get_mut().write_all(&component).map_err(Into::into))
+        }
+        (None, Some((file, entry))) => {
+            let file = exported_name(renamed, file);
+            write_catalog(files, id, |w| synthesize(w, id, file, entry, &icons, bundle))
+        }
+        (None, None) => {
+            warn!("No metainfo or desktop file in image, {id} gets no appstream data");
+            Ok(())
+        }
+    }
+}
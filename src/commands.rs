@@ -1,4 +1,12 @@
+pub mod app_args;
+pub mod bundle;
 pub mod import_container;
+pub mod info;
 pub mod install;
+pub mod list;
 pub mod obs_fetch;
 pub mod pull;
+pub mod runtime_args;
+pub mod signing_args;
+pub mod uninstall;
+pub mod update;
@@ -0,0 +1,72 @@
+use clap;
+use std::path::PathBuf;
+
+use crate::commands::install::get_overrides_path;
+use crate::flatpak::{read_permissions, AppOptions, NativeApp, Permission};
+use crate::oci;
+use crate::Result;
+
+// Options shared by commands building an app
+#[derive(clap::Args)]
+pub struct AppArgs {
+    /// Image directory holding the finished app, e.g. /app or /opt/NAME,
+    /// to build it from instead of its flatpak.yaml [default: from label
+    /// org.opensuse.flatpak.app-subtree]
+    #[arg(long)]
+    app_subtree: Option<PathBuf>,
+    /// Command the app runs, relative to the app subtree's bin or absolute
+    /// in the sandbox [default: from label org.opensuse.flatpak.command]
+    #[arg(long)]
+    command: Option<String>,
+    /// Option of `flatpak build-finish` for the app built from its
+    /// subtree, e.g. --socket=wayland. May be repeated
+    #[arg(long = "finish-arg", allow_hyphen_values = true)]
+    finish_args: Vec<String>,
+}
+
+impl AppArgs {
+    /// Build options of app `id`, filling in what isn't given on command
+    /// line from the image config
+    pub fn options(&self, config: &oci::Config, id: &str) -> Result<AppOptions> {
+        let subtree = self
+            .app_subtree
+            .clone()
+            .or(config.label(oci::LABEL_APP_SUBTREE).ok().map(PathBuf::from));
+
+        let native = match subtree {
+            Some(subtree) => {
+                let command = match &self.command {
+                    Some(command) => command.clone(),
+                    None => config
+                        .label(oci::LABEL_COMMAND)
+                        .map_err(|_| "Building an app from its subtree needs a command")?
+                        .to_string(),
+                };
+                Some(NativeApp {
+                    subtree,
+                    command,
+                    finish_args: self.finish_args.clone(),
+                })
+            }
+            None => None,
+        };
+
+        // the local override file comes last, for admins to have the final say
+        let mut permissions = match config.label(oci::LABEL_PERMISSIONS) {
+            Ok(label) => label
+                .split(',')
+                .filter(|p| !p.trim().is_empty())
+                .map(str::parse)
+                .collect::<std::result::Result<Vec<Permission>, _>>()?,
+            Err(_) => Vec::new(),
+        };
+        permissions.extend(read_permissions(&get_overrides_path().join(id))?);
+
+        Ok(AppOptions {
+            native,
+            permissions,
+            labels: config.config.labels.clone(),
+            ..Default::default()
+        })
+    }
+}
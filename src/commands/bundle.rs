@@ -0,0 +1,107 @@
+use clap;
+use std::env;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tempfile::TempDir;
+
+use crate::commands::install::{build, get_repo_path, BuildArgs};
+use crate::exec::CheckedRun;
+use crate::flatpak::APP_BRANCH;
+use crate::Result;
+
+#[derive(clap::Args)]
+pub struct Args {
+    #[command(flatten)]
+    build: BuildArgs,
+    /// Directory to write the bundles to
+    #[arg(long, default_value = ".")]
+    output_dir: PathBuf,
+    /// URL of the published repo, for installed bundles to get updates from
+    #[arg(long)]
+    repo_url: Option<String>,
+    /// URL of a .flatpakrepo file describing where the runtime comes from
+    #[arg(long)]
+    runtime_repo: Option<String>,
+
+    container: String,
+}
+
+/// flatpak working on the throwaway installation set up by `run`
+fn bundle_flatpak() -> Command {
+    let mut cmd = Command::new("flatpak");
+    cmd.arg("--user");
+    cmd
+}
+
+/// Write bundle `file` of `name` from the publishing repo
+fn build_bundle(
+    args: &Args,
+    key: Option<&Path>,
+    file: &Path,
+    name: &str,
+    branch: &str,
+    arch: &str,
+    runtime: bool,
+) -> Result<()> {
+    println!("Writing {}", file.display());
+
+    let mut cmd = Command::new("flatpak");
+    cmd.arg("build-bundle").arg(format!("--arch={arch}"));
+    if runtime {
+        cmd.arg("--runtime");
+    } else if let Some(url) = &args.runtime_repo {
+        cmd.arg(format!("--runtime-repo={url}"));
+    }
+    if let Some(url) = &args.repo_url {
+        cmd.arg(format!("--repo-url={url}"));
+    }
+    if let Some(key) = key {
+        let mut arg = OsString::from("--gpg-keys=");
+        arg.push(key);
+        cmd.arg(arg);
+    }
+    if let Some(signing) = args.build.signing.signing() {
+        cmd.args(signing.args());
+    }
+    cmd.arg(get_repo_path().as_os_str())
+        .arg(file)
+        .arg(name)
+        .arg(branch)
+        .checked_run()?;
+
+    Ok(())
+}
+
+pub fn run(args: &Args) -> Result<()> {
+    // The app is built against runtimes installed in a throwaway user
+    // installation, leaving the real ones alone
+    let installation = TempDir::new()?;
+    env::set_var("FLATPAK_USER_DIR", installation.path());
+
+    let build = build(&args.build, &args.build.reference(&args.container), bundle_flatpak)?;
+
+    let key = match args.build.signing.signing() {
+        Some(signing) => {
+            let key = installation.path().join("key.gpg");
+            signing.export_public_key(&key)?;
+            Some(key)
+        }
+        None => None,
+    };
+
+    let mut refs = vec![(build.runtime_id.as_str(), build.version.as_str(), true)];
+    if let Some(sdk_id) = &build.sdk_id {
+        refs.push((sdk_id.as_str(), build.version.as_str(), true));
+    }
+    refs.push((build.app_id.as_str(), APP_BRANCH, false));
+
+    for (name, branch, runtime) in refs {
+        let file = args.output_dir.join(format!("{name}.flatpak"));
+        build_bundle(args, key.as_deref(), &file, name, branch, build.arch, runtime)?;
+    }
+
+    println!("Done.");
+
+    Ok(())
+}
@@ -1,33 +1,56 @@
 use clap;
-use std::fs::create_dir_all;
+use std::fs::{create_dir_all, File};
 use std::path::Path;
 use tempfile::TempDir;
 
+use crate::commands::runtime_args::RuntimeArgs;
+use crate::commands::signing_args::SigningArgs;
 use crate::flatpak;
 use crate::obs;
+use crate::oci;
 use crate::Result;
 
 #[derive(clap::Args)]
 pub struct Args {
-    // XXX Those defaults should be derived from image
-    #[arg(long, default_value = "org.openSUSE.OCIPlatform")]
-    id: String,
-    #[arg(long, default_value = "x86_64")]
-    arch: String,
-    #[arg(long, default_value = "1")]
-    version: String,
+    /// Runtime id [default: derived from image appname label]
+    #[arg(long)]
+    id: Option<String>,
+    /// Runtime architecture [default: derived from image architecture]
+    #[arg(long)]
+    arch: Option<String>,
+    /// Runtime version [default: derived from image version label]
+    #[arg(long)]
+    version: Option<String>,
+    /// Image to import from a multi-image archive, by repo tag or index
+    #[arg(long)]
+    image: Option<String>,
+    #[command(flatten)]
+    runtime: RuntimeArgs,
+    /// Also build an SDK runtime from the same image
+    #[arg(long)]
+    sdk: bool,
+    /// Build the SDK runtime from this image of the archive instead, by repo
+    /// tag or index
+    #[arg(long)]
+    sdk_image: Option<String>,
+    /// SDK id [default: derived from image appname label]
+    #[arg(long)]
+    sdk_id: Option<String>,
+    #[command(flatten)]
+    signing: SigningArgs,
 
     image_file: String,
     repo: String,
 }
 
+/// Config of the selected image of an unpacked archive
+fn read_config(img: &obs::ContainerImage, image_dir: &Path) -> Result<oci::Config> {
+    Ok(serde_json::from_reader(File::open(image_dir.join(img.config().unwrap()))?)?)
+}
+
 pub fn run(args: &Args) -> Result<()> {
     println!("Importing {}", args.image_file);
 
-    let id = args.id.as_str();
-    let arch = args.arch.as_str();
-    let version = args.version.as_str();
-
     let work_dir = TempDir::new()?;
     let work_dir_path = work_dir.path();
 
@@ -38,17 +61,79 @@ pub fn run(args: &Args) -> Result<()> {
 
     println!("Unpacking image");
     img.unpack(image_dir.as_path())?;
+    img.select(args.image.as_deref())?;
+
+    let config = read_config(&img, &image_dir)?;
+
+    let id = match &args.id {
+        Some(id) => id.to_owned(),
+        None => flatpak::runtime_id(config.label(oci::LABEL_APPNAME)?),
+    };
+    let arch = match &args.arch {
+        Some(arch) => arch.as_str(),
+        None => config.flatpak_arch()?,
+    };
+    let version = match &args.version {
+        Some(version) => version.as_str(),
+        None => config.label(oci::LABEL_VERSION)?,
+    };
 
-    flatpak::Builder::new()?.build_runtime(
+    let pair = match args.sdk || args.sdk_image.is_some() {
+        true => Some(flatpak::RuntimePair {
+            platform: id.clone(),
+            sdk: match &args.sdk_id {
+                Some(sdk_id) => sdk_id.to_owned(),
+                None => flatpak::sdk_id(config.label(oci::LABEL_APPNAME)?),
+            },
+        }),
+        false => None,
+    };
+
+    let builder = flatpak::Builder::new(args.signing.signing())?;
+
+    let mut opts = args.runtime.options(&config)?;
+    opts.pair = pair.clone();
+    builder.build_runtime(
         &args.repo,
         img.layers()
             .unwrap()
             .iter()
             .map(|l| Path::join(image_dir.as_path(), l)),
-        id,
+        &id,
         arch,
         version,
+        &opts,
     )?;
 
+    if let Some(pair) = pair {
+        let sdk_config = match &args.sdk_image {
+            Some(image) => {
+                img.select(Some(image))?;
+                let sdk_config = read_config(&img, &image_dir)?;
+                if sdk_config.flatpak_arch()? != arch {
+                    return Err(format!("SDK image is not built for {arch}").into());
+                }
+                Some(sdk_config)
+            }
+            None => None,
+        };
+
+        println!("Building SDK");
+        let mut opts = args.runtime.sdk_options(sdk_config.as_ref().unwrap_or(&config))?;
+        let sdk_id = pair.sdk.clone();
+        opts.pair = Some(pair);
+        builder.build_runtime(
+            &args.repo,
+            img.layers()
+                .unwrap()
+                .iter()
+                .map(|l| Path::join(image_dir.as_path(), l)),
+            &sdk_id,
+            arch,
+            version,
+            &opts,
+        )?;
+    }
+
     Ok(())
 }
@@ -0,0 +1,120 @@
+use clap;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+
+use crate::exec::{flatpak, ostree, CheckedRun};
+use crate::oci;
+use crate::Result;
+
+#[derive(clap::Args)]
+pub struct Args {
+    /// Print as JSON
+    #[arg(long)]
+    json: bool,
+
+    /// Installed ref, or the id of an installed app or runtime
+    name: String,
+}
+
+/// Value printed by `ostree show --print-metadata-key` for a string
+fn parse_gvariant_string(value: &str) -> String {
+    let value = value.trim();
+    let value = value
+        .strip_prefix('\'')
+        .and_then(|v| v.strip_suffix('\''))
+        .unwrap_or(value);
+
+    let mut ret = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            ret.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => ret.push('\n'),
+            Some('t') => ret.push('\t'),
+            Some(c) => ret.push(c),
+            None => (),
+        }
+    }
+    ret
+}
+
+/// String metadata `key` of `commit`, if set
+pub fn metadata_key(repo: &Path, commit: &str, key: &str) -> Result<Option<String>> {
+    let output = ostree()
+        .arg("show")
+        .arg("--repo")
+        .arg(repo.as_os_str())
+        .arg(format!("--print-metadata-key={key}"))
+        .arg(commit)
+        .stderr(Stdio::null())
+        .output()?;
+    // ostree fails on missing keys
+    if !output.status.success() {
+        return Ok(None);
+    }
+    Ok(Some(parse_gvariant_string(&String::from_utf8(output.stdout)?)))
+}
+
+/// Ref installed with flatpak
+pub struct Installed {
+    pub flatpak_ref: String,
+    pub commit: String,
+    /// Image it was converted from, if it was
+    pub provenance: Option<oci::Provenance>,
+}
+
+/// Look up installed ref, or id of an installed app or runtime, `name`
+pub fn installed(name: &str) -> Result<Installed> {
+    let flatpak_info = |opt: &str| flatpak().arg("info").arg(opt).arg(name).checked_output();
+
+    let flatpak_ref = flatpak_info("--show-ref")?.trim().to_string();
+    let commit = flatpak_info("--show-commit")?.trim().to_string();
+    // deployed at <installation>/{app,runtime}/<id>/<arch>/<branch>/<commit>
+    let location = PathBuf::from(flatpak_info("--show-location")?.trim());
+    let repo = location
+        .ancestors()
+        .nth(5)
+        .ok_or(format!("Unexpected deploy location {}", location.display()))?
+        .join("repo");
+
+    let provenance = oci::Provenance::from_metadata(|key| metadata_key(&repo, &commit, key))?;
+    Ok(Installed {
+        flatpak_ref,
+        commit,
+        provenance,
+    })
+}
+
+pub fn run(args: &Args) -> Result<()> {
+    let Installed {
+        flatpak_ref,
+        commit,
+        provenance,
+    } = installed(&args.name)?;
+    let provenance = provenance.ok_or(format!("{flatpak_ref} wasn't converted from a registry image"))?;
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&provenance)?);
+        return Ok(());
+    }
+
+    println!("Ref: {flatpak_ref}");
+    println!("Commit: {commit}");
+    println!("Registry: {}", provenance.registry);
+    println!("Repository: {}", provenance.repository);
+    println!("Tag: {}", provenance.tag);
+    println!("Manifest digest: {}", provenance.manifest_digest);
+    println!("Config digest: {}", provenance.config_digest);
+    println!("Layer digests:");
+    for l in provenance.layer_digests.iter() {
+        println!("  {l}");
+    }
+    if let Some(created) = &provenance.created {
+        println!("Created: {created}");
+    }
+
+    Ok(())
+}
@@ -1,8 +1,14 @@
 use clap;
 use std::env;
+use std::ffi::OsString;
 use std::path::PathBuf;
+use std::process::Command;
+use tempfile::NamedTempFile;
 
+use crate::commands::app_args::AppArgs;
 use crate::commands::pull::pull_image;
+use crate::commands::runtime_args::RuntimeArgs;
+use crate::commands::signing_args::SigningArgs;
 use crate::exec::{CheckedRun, flatpak};
 use crate::flatpak;
 use crate::oci;
@@ -10,10 +16,12 @@ use crate::Result;
 
 const FS_REPO_GLOBAL: &str = "/var/lib/flatpak-oci-tools/repo";
 const FS_REPO_USER: &str = ".local/share/flatpak-oci-tools/repo";
+const FS_OVERRIDES_GLOBAL: &str = "/etc/flatpak-oci-tools/overrides";
+const FS_OVERRIDES_USER: &str = ".config/flatpak-oci-tools/overrides";
 
 use crate::IS_USER;
 
-fn get_repo_path() -> PathBuf {
+pub fn get_repo_path() -> PathBuf {
     if IS_USER.get().unwrap().to_owned() {
         // Deprecated due to Windows related issues, not our problem
         #[allow(deprecated)]
@@ -25,7 +33,19 @@ fn get_repo_path() -> PathBuf {
     }
 }
 
-fn get_repo_name() -> String {
+/// Directory of the per app id permission override files
+pub fn get_overrides_path() -> PathBuf {
+    if IS_USER.get().unwrap().to_owned() {
+        #[allow(deprecated)]
+        let mut r = env::home_dir().unwrap();
+        r.push(FS_OVERRIDES_USER);
+        r
+    } else {
+        PathBuf::from(FS_OVERRIDES_GLOBAL)
+    }
+}
+
+pub fn get_repo_name() -> String {
     if IS_USER.get().unwrap().to_owned() {
 	"oci-tools-user".to_string()
     } else {
@@ -33,84 +53,256 @@ fn get_repo_name() -> String {
     }
 }
 
+/// Refs of the apps installed from the publishing repo, `app/ID/ARCH/BRANCH`
+pub fn installed_apps() -> Result<Vec<String>> {
+    installed_refs("app")
+}
+
+/// Refs of the runtimes installed from the publishing repo,
+/// `runtime/ID/ARCH/BRANCH`
+pub fn installed_runtimes() -> Result<Vec<String>> {
+    installed_refs("runtime")
+}
+
+fn installed_refs(kind: &str) -> Result<Vec<String>> {
+    let list = flatpak()
+        .arg("list")
+        .arg(format!("--{kind}"))
+        .arg("--columns=ref,origin")
+        .checked_output()?;
+    let origin = get_repo_name();
+
+    // flatpak list leaves the kind out of refs
+    Ok(list
+        .lines()
+        .filter_map(|l| {
+            let mut cols = l.split_whitespace();
+            match (cols.next(), cols.next()) {
+                (Some(r), Some(o)) if o == origin => Some(format!("{kind}/{r}")),
+                _ => None,
+            }
+        })
+        .collect())
+}
+
+// Where containers are looked up
 #[derive(clap::Args)]
-pub struct Args {
+pub struct RegistryArgs {
     #[arg(long, default_value = "https://registry.opensuse.org")]
     registry: String,
     #[arg(long, default_value = "home:yudaike:flatpak-oci-container")]
     project: String,
     #[arg(long, default_value = "images")]
     repo: String,
+}
+
+// Options of commands converting a container from a registry
+#[derive(clap::Args)]
+pub struct BuildArgs {
+    #[command(flatten)]
+    pub registry: RegistryArgs,
+    #[command(flatten)]
+    runtime: RuntimeArgs,
+    #[command(flatten)]
+    app: AppArgs,
+    /// Also build an SDK runtime from the same image
+    #[arg(long)]
+    sdk: bool,
+    /// Build the SDK runtime from this image instead
+    #[arg(long)]
+    sdk_image: Option<String>,
+    #[command(flatten)]
+    pub signing: SigningArgs,
+}
+
+#[derive(clap::Args)]
+pub struct Args {
+    #[command(flatten)]
+    build: BuildArgs,
 
     container: String,
 }
 
-pub fn run(args: &Args) -> Result<()> {
-    let (container, tag) = args
-        .container
-        .rsplit_once(":")
-        .unwrap_or((&args.container, "latest"));
+/// Refs built from a container
+pub struct Build {
+    pub runtime_id: String,
+    pub sdk_id: Option<String>,
+    pub app_id: String,
+    pub arch: &'static str,
+    pub version: String,
+}
+
+/// Image pulled from the registry
+struct Fetched {
+    config: oci::Config,
+    layers: Vec<PathBuf>,
+    provenance: oci::Provenance,
+}
+
+impl RegistryArgs {
+    /// Image `container` of the project in the registry, `NAME[:TAG]`
+    pub fn reference(&self, container: &str) -> oci::Reference {
+        let (container, tag) = container
+            .rsplit_once(":")
+            .unwrap_or((container, "latest"));
 
-    let container_name = format!(
-        "{proj}/{repo}/{container}",
-        proj = args.project.replace(":", "/"),
-        repo = args.repo
-    );
+        oci::Reference {
+            registry: self.registry.clone(),
+            repository: format!(
+                "{proj}/{repo}/{container}",
+                proj = self.project.replace(":", "/"),
+                repo = self.repo
+            ),
+            tag: tag.to_string(),
+        }
+    }
+}
+
+impl BuildArgs {
+    pub fn reference(&self, container: &str) -> oci::Reference {
+        self.registry.reference(container)
+    }
+}
 
-    let api = oci::Api::new(&args.registry)?;
-    let manifest = api.get_manifest(&container_name, &tag)?;
-    let config = api.get_config(&container_name, &manifest.config)?;
+/// Fetch manifest, config and layers of an image to be installed with
+/// `installation`
+fn fetch(api: &oci::Api, image: &oci::Reference, installation: fn() -> Command) -> Result<Fetched> {
+    let manifest = api.get_manifest(&image.repository, &image.tag)?;
+    let config = api.get_config(&image.repository, &manifest.config)?;
+    flatpak::check_arch(installation, config.flatpak_arch()?)?;
 
     println!("Pulling fs layers...");
-    let layers = pull_image(&api, &container_name, &manifest)?;
-
-    let appname = config
-        .config
-        .labels
-        .get("org.opensuse.flatpak.appname")
-        .ok_or("Missing label: org.opensuse.flatpak.appname")?;
-
-    let runtime_id = format!("org.openSUSE.Platform.{appname}");
-    let app_id = format!("org.openSUSE.App.{appname}");
-    let arch = match config.architecture.as_str() {
-        "amd64" => Ok("x86_64"),
-        other => Err(format!("Unsupported architecture: {other}")),
-    }?;
-    let version = config
-        .config
-        .labels
-        .get("org.opencontainers.image.version")
-        .ok_or("Missing label: org.opencontainers.image.version")?;
+    let layers = pull_image(api, &image.repository, &manifest)?;
+
+    let provenance = oci::Provenance::new(image, &manifest, &config);
+    Ok(Fetched { config, layers, provenance })
+}
+
+/// Pull an image and build its runtime and app into the publishing repo.
+/// The runtimes are installed, or updated, with `installation`, for the app
+/// to be built against them.
+pub fn build(args: &BuildArgs, reference: &oci::Reference, installation: fn() -> Command) -> Result<Build> {
+    let api = oci::Api::new(&reference.registry)?;
+    let image = fetch(&api, reference, installation)?;
+    let (config, layers) = (&image.config, &image.layers);
+
+    let appname = config.label(oci::LABEL_APPNAME)?;
+
+    let runtime_id = flatpak::runtime_id(appname);
+    let sdk_id = flatpak::sdk_id(appname);
+    let app_id = flatpak::app_id(appname);
+    let arch = config.flatpak_arch()?;
+    let version = config.label(oci::LABEL_VERSION)?;
+
+    let pair = (args.sdk || args.sdk_image.is_some()).then(|| flatpak::RuntimePair {
+        platform: runtime_id.clone(),
+        sdk: sdk_id.clone(),
+    });
 
     let repo = get_repo_path();
 
     flatpak::ensure_repo(repo.as_path())?;
-    let builder = flatpak::Builder::new()?;
+    let signing = args.signing.signing();
+    let builder = flatpak::Builder::new(signing.clone())?;
 
     println!("Building runtime");
-    builder.build_runtime(repo.as_path(), layers, &runtime_id, arch, version)?;
+    let mut opts = args.runtime.options(config)?;
+    opts.pair = pair.clone();
+    opts.provenance = Some(image.provenance.clone());
+    builder.build_runtime(repo.as_path(), layers, &runtime_id, arch, version, &opts)?;
+
+    if pair.is_some() {
+        let sdk_image = match &args.sdk_image {
+            Some(image) => Some(fetch(&api, &args.reference(image), installation)?),
+            None => None,
+        };
+        let sdk_image = sdk_image.as_ref().unwrap_or(&image);
+        let (sdk_config, sdk_layers) = (&sdk_image.config, &sdk_image.layers);
+        if sdk_config.flatpak_arch()? != arch {
+            return Err(format!("SDK image is not built for {arch}").into());
+        }
+
+        println!("Building SDK");
+        let mut opts = args.runtime.sdk_options(sdk_config)?;
+        opts.pair = pair.clone();
+        opts.provenance = Some(sdk_image.provenance.clone());
+        builder.build_runtime(repo.as_path(), sdk_layers, &sdk_id, arch, version, &opts)?;
+    }
 
     println!("Installing runtime");
-    flatpak().arg("remote-add")
-        .arg("--if-not-exists")
-        .arg("--no-gpg-verify")
-        .arg(get_repo_name())
-        .arg(get_repo_path().as_os_str())
-        .checked_run()?;
-    flatpak().arg("install")
-        .arg("--assumeyes")
+    // an existing remote may predate signing being configured, or the
+    // other way round
+    let remote_exists = installation()
+        .args(["remotes", "--columns=name"])
+        .checked_output()?
+        .lines()
+        .any(|l| l.trim() == get_repo_name());
+    let mut remote = installation();
+    match remote_exists {
+        true => remote.arg("remote-modify"),
+        false => remote.arg("remote-add"),
+    };
+    // keep the key file around until the remote is set up
+    let key = match &signing {
+        Some(signing) => {
+            let key = NamedTempFile::new()?;
+            signing.export_public_key(key.path())?;
+            if remote_exists {
+                remote.arg("--gpg-verify");
+            }
+            let mut arg = OsString::from("--gpg-import=");
+            arg.push(key.path());
+            remote.arg(arg);
+            Some(key)
+        }
+        None => {
+            remote.arg("--no-gpg-verify");
+            None
+        }
+    };
+    remote.arg(get_repo_name());
+    if !remote_exists {
+        remote.arg(get_repo_path().as_os_str());
+    }
+    remote.checked_run()?;
+    drop(key);
+    installation().arg("install")
+        .args(["--assumeyes", "--or-update"])
         .arg(get_repo_name())
         .arg(format!("runtime/{runtime_id}/{arch}/{version}"))
         .checked_run()?;
+    if pair.is_some() {
+        installation().arg("install")
+            .args(["--assumeyes", "--or-update"])
+            .arg(get_repo_name())
+            .arg(format!("runtime/{sdk_id}/{arch}/{version}"))
+            .checked_run()?;
+    }
 
     println!("Building application");
-    builder.build_app(repo.as_path(), &app_id, &runtime_id, arch, version)?;
+    let mut opts = args.app.options(config, &app_id)?;
+    opts.appstream = args.runtime.appstream();
+    opts.sdk = pair.as_ref().map(|p| p.sdk.clone());
+    opts.provenance = Some(image.provenance.clone());
+    builder.build_app(repo.as_path(), &app_id, &runtime_id, arch, version, &opts)?;
+
+    Ok(Build {
+        runtime_id,
+        sdk_id: pair.map(|p| p.sdk),
+        app_id,
+        arch,
+        version: version.to_string(),
+    })
+}
+
+pub fn run(args: &Args) -> Result<()> {
+    let build = build(&args.build, &args.build.reference(&args.container), flatpak)?;
 
     println!("Installing application");
     flatpak().arg("install")
         .arg("--assumeyes")
         .arg(get_repo_name())
-        .arg(format!("app/{app_id}/{arch}/master"))
+        .arg(format!("app/{}/{}/{}", build.app_id, build.arch, flatpak::APP_BRANCH))
         .checked_run()?;
 
     println!("Done.");
@@ -0,0 +1,145 @@
+use clap;
+use serde::Serialize;
+
+use crate::commands::info::metadata_key;
+use crate::commands::install::{get_repo_name, get_repo_path};
+use crate::exec::{flatpak, CheckedRun};
+use crate::flatpak::{self, Metadata};
+use crate::oci;
+use crate::Result;
+
+#[derive(clap::Args)]
+pub struct Args {
+    /// Print as JSON
+    #[arg(long)]
+    json: bool,
+    /// Ask the registry whether images changed since they were converted
+    #[arg(long)]
+    check_updates: bool,
+}
+
+/// App in the repo and where it came from
+#[derive(Serialize)]
+struct Entry {
+    app_id: String,
+    runtime_id: Option<String>,
+    version: Option<String>,
+    image: Option<String>,
+    digest: Option<String>,
+    installed: bool,
+    installed_size: Option<String>,
+    /// Only known with `--check-updates`
+    update_available: Option<bool>,
+}
+
+/// Installed app refs from the publishing repo, `app/ID/ARCH/BRANCH` like
+/// the repo's, with their installed size
+fn installed_sizes() -> Result<Vec<(String, String)>> {
+    let list = flatpak()
+        .args(["list", "--app", "--columns=ref,origin,size"])
+        .checked_output()?;
+    let origin = get_repo_name();
+
+    Ok(list
+        .lines()
+        .filter_map(|l| {
+            let mut cols = l.split_whitespace();
+            let (r, o) = (cols.next()?, cols.next()?);
+            // flatpak list leaves the kind out of refs
+            (o == origin).then(|| (format!("app/{r}"), cols.collect::<Vec<_>>().join(" ")))
+        })
+        .collect())
+}
+
+fn entries(check_updates: bool) -> Result<Vec<Entry>> {
+    let repo = get_repo_path();
+    let installed = installed_sizes()?;
+
+    let mut ret = Vec::new();
+    for r in flatpak::repo_refs(&repo)?.iter().filter(|r| r.starts_with("app/")) {
+        let app_id = r.split('/').nth(1).unwrap_or_default().to_string();
+
+        // runtime the app was built against, ID/ARCH/VERSION
+        let runtime = match metadata_key(&repo, r, "xa.metadata")? {
+            Some(metadata) => Metadata::parse(&metadata)?
+                .get("Application", "runtime")
+                .map(str::to_string),
+            None => None,
+        };
+        let mut runtime = runtime.as_deref().unwrap_or_default().split('/');
+        let (runtime_id, version) = (runtime.next(), runtime.nth(1));
+
+        let provenance = oci::Provenance::from_metadata(|key| metadata_key(&repo, r, key))?;
+        let update_available = match &provenance {
+            Some(p) if check_updates => {
+                let manifest = oci::Api::new(&p.registry)?.get_manifest(&p.repository, &p.tag)?;
+                Some(manifest.digest != p.manifest_digest)
+            }
+            _ => None,
+        };
+        let installed_size = installed.iter().find(|(i, _)| i == r).map(|(_, size)| size.clone());
+
+        ret.push(Entry {
+            app_id,
+            runtime_id: runtime_id.filter(|i| !i.is_empty()).map(str::to_string),
+            version: version.map(str::to_string),
+            image: provenance
+                .as_ref()
+                .map(|p| format!("{}/{}:{}", p.registry, p.repository, p.tag)),
+            digest: provenance.map(|p| p.manifest_digest),
+            installed: installed_size.is_some(),
+            installed_size,
+            update_available,
+        });
+    }
+    Ok(ret)
+}
+
+fn print_table(entries: &[Entry]) {
+    let header = ["APP", "RUNTIME", "VERSION", "IMAGE", "DIGEST", "INSTALLED", "UPDATE"];
+    let or_dash = |v: &Option<String>| v.clone().unwrap_or("-".to_string());
+    let rows: Vec<[String; 7]> = entries
+        .iter()
+        .map(|e| {
+            [
+                e.app_id.clone(),
+                or_dash(&e.runtime_id),
+                or_dash(&e.version),
+                or_dash(&e.image),
+                // long enough to tell images apart
+                or_dash(&e.digest.as_ref().map(|d| d.chars().take(19).collect())),
+                or_dash(&e.installed_size),
+                match e.update_available {
+                    Some(true) => "yes".to_string(),
+                    Some(false) => "no".to_string(),
+                    None => "-".to_string(),
+                },
+            ]
+        })
+        .collect();
+
+    let header = header.map(str::to_string);
+    let mut widths = [0; 7];
+    for row in std::iter::once(&header).chain(rows.iter()) {
+        for (w, col) in widths.iter_mut().zip(row.iter()) {
+            *w = (*w).max(col.len());
+        }
+    }
+
+    for row in std::iter::once(&header).chain(rows.iter()) {
+        let line: Vec<String> = row.iter().zip(widths.iter()).map(|(c, w)| format!("{c:w$}")).collect();
+        println!("{}", line.join("  ").trim_end());
+    }
+}
+
+pub fn run(args: &Args) -> Result<()> {
+    let entries = entries(args.check_updates)?;
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&entries)?);
+    } else {
+        print_table(&entries);
+    }
+
+    Ok(())
+}
@@ -42,7 +42,7 @@ pub fn run(args: &Args) -> Result<()> {
         .collect();
 
     if candidates.len() != 1 {
-        if candidates.len() == 0 {
+        if candidates.is_empty() {
             println!("No candidates available");
         } else {
             println!("Multiple candidates detected");
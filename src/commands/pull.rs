@@ -24,7 +24,7 @@ pub struct Args {
 }
 
 /// Get cache location for a layer
-fn get_layer_path(layer: &str) -> PathBuf {
+pub fn get_layer_path(layer: &str) -> PathBuf {
     let mut ret = if crate::IS_USER.get().unwrap().to_owned() {
 	// Deprecated due to Windows related issues, not our problem
 	#[allow(deprecated)]
@@ -66,7 +66,7 @@ pub fn pull_image(api: &oci::Api, container: &str, manifest: &oci::Manifest) ->
     let mut ret: Vec<PathBuf> = Vec::with_capacity(manifest.layers.len());
 
     for l in manifest.layers.iter() {
-	ret.push(pull_layer(&api, container, &l)?);
+	ret.push(pull_layer(api, container, l)?);
     }
 
     Ok(ret)
@@ -85,7 +85,7 @@ pub fn run(args: &Args) -> Result<()> {
     );
 
     let api = oci::Api::new(&args.registry)?;
-    let manifest = api.get_manifest(&container_name, &tag)?;
+    let manifest = api.get_manifest(&container_name, tag)?;
 
     pull_image(&api, &container_name, &manifest)?;
 
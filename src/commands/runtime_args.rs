@@ -0,0 +1,153 @@
+use clap;
+use std::collections::BTreeMap;
+use std::fs::read_to_string;
+use std::path::PathBuf;
+
+use crate::appstream;
+use crate::environment;
+use crate::flatpak::{Extension, Metadata, RuntimeOptions, Subtree, DEFAULT_SUBTREES};
+use crate::oci;
+use crate::sanitize;
+use crate::triggers::Trigger;
+use crate::Result;
+
+// Options shared by commands building a runtime. Not a doc comment, clap
+// would take it as description of the commands flattening it.
+#[derive(clap::Args)]
+pub struct RuntimeArgs {
+    /// How to deal with setuid/setgid, device and world-writable entries
+    #[arg(long, value_enum, default_value_t)]
+    sanitize: sanitize::Policy,
+    /// Write a JSON report of the sanitization to this file
+    #[arg(long)]
+    sanitize_report: Option<PathBuf>,
+    /// Image directory to include in the runtime as SRC[:DEST], DEST being
+    /// relative to the runtime's /usr. May be repeated, later ones are
+    /// merged over earlier ones. [default: from label
+    /// org.opensuse.flatpak.subtrees, or /usr and /etc:etc]
+    #[arg(long = "subtree")]
+    subtrees: Vec<Subtree>,
+    /// Don't regenerate this cache in the runtime, may be repeated
+    #[arg(long = "skip-trigger", value_enum)]
+    skip_triggers: Vec<Trigger>,
+    /// Keyfile with [Extension NAME] groups to declare in the runtime, in
+    /// addition to those from org.opensuse.flatpak.extension.* labels
+    #[arg(long)]
+    extensions: Option<PathBuf>,
+    /// Don't generate appstream data for software centers
+    #[arg(long)]
+    no_appstream: bool,
+    /// Static deltas to keep per runtime when updating it in the repo, 0 to
+    /// not generate any
+    #[arg(long, default_value_t = 3)]
+    max_deltas: usize,
+}
+
+/// Extensions declared by `org.opensuse.flatpak.extension.<name>.<key>` labels
+fn label_extensions(config: &oci::Config) -> Result<Vec<Extension>> {
+    let mut exts: BTreeMap<&str, Vec<(String, String)>> = BTreeMap::new();
+    for (label, value) in config.config.labels.iter() {
+        if let Some((name, key)) = label
+            .strip_prefix(oci::LABEL_EXTENSION_PREFIX)
+            .and_then(|l| l.rsplit_once('.'))
+        {
+            exts.entry(name)
+                .or_default()
+                .push((key.to_string(), value.to_string()));
+        }
+    }
+
+    exts.into_iter()
+        .map(|(name, mut keys)| {
+            keys.sort();
+            Extension::new(name, keys)
+        })
+        .collect()
+}
+
+/// Runtime description from the image's OCI annotations
+fn appstream_info(config: &oci::Config) -> appstream::Info {
+    let name = config
+        .label(oci::LABEL_TITLE)
+        .or(config.label(oci::LABEL_APPNAME))
+        .unwrap_or("Container image");
+    let summary = match config.label(oci::LABEL_DESCRIPTION) {
+        Ok(description) => description.to_string(),
+        Err(_) => format!("Runtime for {name}"),
+    };
+    appstream::Info {
+        name: name.to_string(),
+        summary,
+    }
+}
+
+impl RuntimeArgs {
+    /// Whether appstream data is generated
+    pub fn appstream(&self) -> bool {
+        !self.no_appstream
+    }
+
+    /// Build options, filling in what isn't given on command line from
+    /// the image config
+    pub fn options(&self, config: &oci::Config) -> Result<RuntimeOptions> {
+        let subtrees = if !self.subtrees.is_empty() {
+            self.subtrees.clone()
+        } else {
+            let specs = match config.label(oci::LABEL_SUBTREES) {
+                Ok(label) => label.split(',').map(str::trim).collect(),
+                Err(_) => DEFAULT_SUBTREES.to_vec(),
+            };
+            specs
+                .into_iter()
+                .map(|s| s.parse())
+                .collect::<std::result::Result<_, _>>()?
+        };
+
+        let environment = environment::translate(&config.config.env, &subtrees);
+
+        let mut extensions = label_extensions(config)?;
+        if let Some(path) = &self.extensions {
+            // extensions from the build config replace those of the image
+            for ext in Extension::from_metadata(&Metadata::parse(&read_to_string(path)?)?)? {
+                extensions.retain(|e| e.name != ext.name);
+                extensions.push(ext);
+            }
+        }
+
+        Ok(RuntimeOptions {
+            sanitize: self.sanitize,
+            sanitize_report: self.sanitize_report.clone(),
+            subtrees,
+            triggers: Trigger::ALL
+                .iter()
+                .filter(|t| !self.skip_triggers.contains(t))
+                .copied()
+                .collect(),
+            environment,
+            extensions,
+            pair: None,
+            appstream: self.appstream().then(|| appstream_info(config)),
+            max_deltas: self.max_deltas,
+            provenance: None,
+        })
+    }
+
+    /// Like `options`, for the SDK runtime built along a platform
+    pub fn sdk_options(&self, config: &oci::Config) -> Result<RuntimeOptions> {
+        let mut ret = self.options(config)?;
+        ret.sanitize_report = ret.sanitize_report.map(|p| {
+            let mut name = p.file_stem().unwrap_or_default().to_os_string();
+            name.push(".sdk");
+            if let Some(ext) = p.extension() {
+                name.push(".");
+                name.push(ext);
+            }
+            p.with_file_name(name)
+        });
+        if let Some(info) = ret.appstream.as_mut() {
+            info.summary = format!("Development files for {}", info.name);
+            info.name = format!("{} SDK", info.name);
+        }
+        Ok(ret)
+    }
+}
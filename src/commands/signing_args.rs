@@ -0,0 +1,24 @@
+use clap;
+use std::path::PathBuf;
+
+use crate::flatpak::Signing;
+
+// Options of commands publishing to a repo
+#[derive(clap::Args)]
+pub struct SigningArgs {
+    /// GPG key id to sign commits and the repo summary with
+    #[arg(long = "gpg-sign", value_name = "KEYID")]
+    key: Option<String>,
+    /// GPG home directory holding the signing key
+    #[arg(long, requires = "key")]
+    gpg_homedir: Option<PathBuf>,
+}
+
+impl SigningArgs {
+    pub fn signing(&self) -> Option<Signing> {
+        self.key.as_ref().map(|key| Signing {
+            key: key.clone(),
+            homedir: self.gpg_homedir.clone(),
+        })
+    }
+}
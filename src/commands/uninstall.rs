@@ -0,0 +1,101 @@
+use clap;
+use std::collections::HashSet;
+use std::fs::remove_file;
+
+use crate::commands::info::installed;
+use crate::commands::install::{get_repo_name, get_repo_path, installed_apps, installed_runtimes, RegistryArgs};
+use crate::commands::pull::get_layer_path;
+use crate::commands::signing_args::SigningArgs;
+use crate::exec::{flatpak, ostree, CheckedRun};
+use crate::flatpak;
+use crate::oci;
+use crate::Result;
+
+#[derive(clap::Args)]
+pub struct Args {
+    #[command(flatten)]
+    registry: RegistryArgs,
+    #[command(flatten)]
+    signing: SigningArgs,
+
+    container: String,
+}
+
+/// Id part of a `kind/id/arch/branch` ref
+fn ref_id(r: &str) -> &str {
+    r.split('/').nth(1).unwrap_or_default()
+}
+
+pub fn run(args: &Args) -> Result<()> {
+    let reference = args.registry.reference(&args.container);
+
+    let mut ours: Vec<(String, Option<oci::Provenance>)> = Vec::new();
+    for r in installed_apps()?.into_iter().chain(installed_runtimes()?) {
+        let provenance = installed(&r)?.provenance;
+        ours.push((r, provenance));
+    }
+
+    let app_id = ours
+        .iter()
+        .find(|(r, p)| r.starts_with("app/") && p.as_ref().is_some_and(|p| p.is(&reference)))
+        .map(|(r, _)| ref_id(r).to_string())
+        .ok_or(format!("{} is not installed", args.container))?;
+    let appname = flatpak::appname(&app_id).ok_or(format!("{app_id} wasn't installed by this tool"))?;
+    let ids = [app_id.clone(), flatpak::runtime_id(appname), flatpak::sdk_id(appname)];
+
+    let (doomed, kept): (Vec<_>, Vec<_>) = ours.into_iter().partition(|(r, _)| ids.iter().any(|id| id == ref_id(r)));
+
+    println!("Uninstalling {app_id}");
+    flatpak()
+        .args(["uninstall", "--assumeyes"])
+        .args(doomed.iter().map(|(r, _)| r))
+        .checked_run()?;
+
+    println!("Removing refs from repo");
+    let repo = get_repo_path();
+    let refs: Vec<String> = flatpak::repo_refs(&repo)?
+        .into_iter()
+        .filter(|r| (r.starts_with("app/") || r.starts_with("runtime/")) && ids.iter().any(|id| id == ref_id(r)))
+        .collect();
+    if !refs.is_empty() {
+        ostree()
+            .args(["refs", "--repo"])
+            .arg(repo.as_os_str())
+            .arg("--delete")
+            .args(refs.iter())
+            .checked_run()?;
+    }
+    ostree()
+        .args(["prune", "--refs-only", "--repo"])
+        .arg(repo.as_os_str())
+        .checked_run()?;
+    flatpak::update_repo(&repo, args.signing.signing().as_ref())?;
+
+    // layers shared with other images stay cached
+    let layers = |refs: &[(String, Option<oci::Provenance>)]| -> HashSet<String> {
+        refs.iter()
+            .filter_map(|(_, p)| p.as_ref())
+            .flat_map(|p| p.layer_digests.iter().cloned())
+            .collect()
+    };
+    let in_use = layers(&kept);
+    for digest in layers(&doomed).difference(&in_use) {
+        let path = get_layer_path(digest);
+        if path.exists() {
+            println!("Releasing layer {digest}");
+            remove_file(path)?;
+        }
+    }
+
+    if kept.is_empty() {
+        println!("Removing remote {}", get_repo_name());
+        flatpak()
+            .arg("remote-delete")
+            .arg(get_repo_name())
+            .checked_run()?;
+    }
+
+    println!("Done.");
+
+    Ok(())
+}
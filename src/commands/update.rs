@@ -0,0 +1,69 @@
+use clap;
+
+use crate::commands::info::installed;
+use crate::commands::install::{build, installed_apps, BuildArgs};
+use crate::exec::{flatpak, CheckedRun};
+use crate::flatpak::APP_BRANCH;
+use crate::oci;
+use crate::Result;
+
+#[derive(clap::Args)]
+pub struct Args {
+    #[command(flatten)]
+    build: BuildArgs,
+
+    /// Containers to update [default: all apps installed by this tool]
+    containers: Vec<String>,
+}
+
+/// Installed app and the image it was converted from
+struct Target {
+    app_ref: String,
+    provenance: oci::Provenance,
+}
+
+/// Installed apps with their provenance
+fn targets() -> Result<Vec<Target>> {
+    let mut ret = Vec::new();
+    for app_ref in installed_apps()? {
+        match installed(&app_ref)?.provenance {
+            Some(provenance) => ret.push(Target { app_ref, provenance }),
+            None => println!("{app_ref} has no provenance, skipping"),
+        }
+    }
+    Ok(ret)
+}
+
+pub fn run(args: &Args) -> Result<()> {
+    let mut targets = targets()?;
+    if !args.containers.is_empty() {
+        let wanted: Vec<oci::Reference> = args.containers.iter().map(|c| args.build.reference(c)).collect();
+        for w in wanted.iter() {
+            if !targets.iter().any(|t| t.provenance.is(w)) {
+                return Err(format!("{}:{} is not installed", w.repository, w.tag).into());
+            }
+        }
+        targets.retain(|t| wanted.iter().any(|w| t.provenance.is(w)));
+    }
+
+    for target in targets {
+        let reference = target.provenance.reference();
+        let api = oci::Api::new(&reference.registry)?;
+        let manifest = api.get_manifest(&reference.repository, &reference.tag)?;
+        if manifest.digest == target.provenance.manifest_digest {
+            println!("{} is up to date", target.app_ref);
+            continue;
+        }
+
+        println!("Updating {}", target.app_ref);
+        let build = build(&args.build, &reference, flatpak)?;
+        flatpak().arg("update")
+            .arg("--assumeyes")
+            .arg(format!("app/{}/{}/{}", build.app_id, build.arch, APP_BRANCH))
+            .checked_run()?;
+    }
+
+    println!("Done.");
+
+    Ok(())
+}
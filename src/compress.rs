@@ -0,0 +1,32 @@
+use flate2::read::MultiGzDecoder;
+use log::debug;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::Path;
+use xz2::read::XzDecoder;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const XZ_MAGIC: &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0x00];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// Open a file, transparently decompressing it if it's compressed with
+/// gzip, xz or zstd. Anything else is passed through as is.
+pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Box<dyn Read>> {
+    let mut file = BufReader::new(File::open(path.as_ref())?);
+    let magic = file.fill_buf()?;
+
+    let ret: Box<dyn Read> = if magic.starts_with(GZIP_MAGIC) {
+        debug!("{} is gzip compressed", path.as_ref().display());
+        Box::new(MultiGzDecoder::new(file))
+    } else if magic.starts_with(XZ_MAGIC) {
+        debug!("{} is xz compressed", path.as_ref().display());
+        Box::new(XzDecoder::new_multi_decoder(file))
+    } else if magic.starts_with(ZSTD_MAGIC) {
+        debug!("{} is zstd compressed", path.as_ref().display());
+        Box::new(zstd::Decoder::with_buffer(file)?)
+    } else {
+        Box::new(file)
+    };
+
+    Ok(ret)
+}
@@ -0,0 +1,93 @@
+//! Translation of the image's `Env` to a runtime's `[Environment]`
+
+use log::debug;
+use std::path::Path;
+
+use crate::flatpak::Subtree;
+
+/// Variables describing the host or the container session rather than
+/// the image content. Flatpak sets those itself.
+const DROPPED: &[&str] = &[
+    "_",
+    "container",
+    "DBUS_SESSION_BUS_ADDRESS",
+    "DISPLAY",
+    "HOME",
+    "HOSTNAME",
+    "LANG",
+    "LANGUAGE",
+    "LOGNAME",
+    "MAIL",
+    "OLDPWD",
+    "PWD",
+    "SHELL",
+    "SHLVL",
+    "TERM",
+    "TZ",
+    "USER",
+    "WAYLAND_DISPLAY",
+    "XDG_RUNTIME_DIR",
+];
+
+/// Search paths that have to keep the application's directory in front
+const APP_DIRS: &[(&str, &str)] = &[
+    ("PATH", "/app/bin"),
+    ("LD_LIBRARY_PATH", "/app/lib"),
+    ("XDG_DATA_DIRS", "/app/share"),
+];
+
+/// Directories the sandbox provides on its own
+const SANDBOX_DIRS: &[&str] = &["/app", "/dev", "/home", "/proc", "/run", "/sys", "/tmp", "/var"];
+
+/// Path of image directory `dir` inside the sandbox, if it's there
+fn translate_path(dir: &str, subtrees: &[Subtree]) -> Option<String> {
+    match Subtree::locate(subtrees, Path::new(dir)) {
+        Some(rel) if rel.as_os_str().is_empty() => Some("/usr".to_string()),
+        Some(rel) => Some(Path::new("/usr").join(rel).to_string_lossy().into_owned()),
+        None if SANDBOX_DIRS.iter().any(|d| Path::new(dir).starts_with(d)) => Some(dir.to_string()),
+        None => None,
+    }
+}
+
+/// Translate `KEY=VALUE` entries of an image config. Paths are moved to
+/// where the runtime puts them, and dropped if they are not part of it.
+pub fn translate(env: &[String], subtrees: &[Subtree]) -> Vec<(String, String)> {
+    let mut ret = Vec::new();
+
+    for var in env {
+        let (key, value) = match var.split_once('=') {
+            Some(kv) => kv,
+            None => continue,
+        };
+        if DROPPED.contains(&key) || key.starts_with("LC_") {
+            debug!("Dropping environment variable {key}");
+            continue;
+        }
+
+        let is_paths = !value.is_empty() && value.split(':').all(|p| p.starts_with('/'));
+        if !is_paths {
+            ret.push((key.to_string(), value.to_string()));
+            continue;
+        }
+
+        let mut paths: Vec<String> = Vec::new();
+        if let Some((_, app_dir)) = APP_DIRS.iter().find(|(k, _)| *k == key) {
+            paths.push(app_dir.to_string());
+        }
+        for p in value.split(':') {
+            match translate_path(p, subtrees) {
+                Some(p) if !paths.contains(&p) => paths.push(p),
+                Some(_) => (),
+                None => debug!("Dropping {p} from {key}, not part of the runtime"),
+            }
+        }
+
+        if paths.is_empty() {
+            debug!("Dropping environment variable {key}");
+        } else {
+            ret.push((key.to_string(), paths.join(":")));
+        }
+    }
+
+    ret
+}
@@ -1,7 +1,8 @@
 use log::debug;
 use std::ffi::{OsStr, OsString};
 use std::io;
-use std::process::Command;
+use std::path::Path;
+use std::process::{Command, Stdio};
 
 use crate::IS_USER;
 
@@ -17,6 +18,23 @@ pub fn flatpak() -> Command {
     cmd
 }
 
+/// Sandbox with runtime tree `files` mounted at `/usr`, laid out as flatpak
+/// does. Append the command to run inside.
+pub fn bwrap<P: AsRef<Path>>(files: P) -> Command {
+    let mut cmd = Command::new("bwrap");
+    cmd.arg("--bind")
+        .arg(files.as_ref().as_os_str())
+        .arg("/usr");
+    for dir in ["bin", "sbin", "lib", "lib64", "etc"] {
+        cmd.args(["--symlink", &format!("usr/{dir}"), &format!("/{dir}")]);
+    }
+    cmd.args(["--proc", "/proc", "--dev", "/dev"])
+        .args(["--tmpfs", "/tmp", "--tmpfs", "/var"])
+        .args(["--unshare-all", "--die-with-parent", "--clearenv"])
+        .args(["--setenv", "PATH", "/usr/bin:/usr/sbin"]);
+    cmd
+}
+
 fn join_args<Sep: AsRef<OsStr>>(cmd: &Command, sep: Sep) -> OsString {
     match cmd.get_args().count() {
         0 => cmd.get_program().to_owned(),
@@ -38,6 +56,8 @@ fn join_args<Sep: AsRef<OsStr>>(cmd: &Command, sep: Sep) -> OsString {
 
 pub trait CheckedRun {
     fn checked_run(&mut self) -> io::Result<()>;
+    /// Run, returning what the command wrote to stdout
+    fn checked_output(&mut self) -> io::Result<String>;
 }
 
 impl CheckedRun for Command {
@@ -45,28 +65,44 @@ impl CheckedRun for Command {
     {
         debug!("Running command: {}", join_args(self, " ").to_string_lossy());
 
-        let mut child = self.spawn().or_else(|err| {
-            Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!(
-                    "Failed to spawn command {}: {}",
-                    self.get_program().to_string_lossy(),
-                    err
-                ),
+        let mut child = self.spawn().map_err(|err| {
+            io::Error::other(format!(
+                "Failed to spawn command {}: {}",
+                self.get_program().to_string_lossy(),
+                err
             ))
         })?;
 
         let ecode = child.wait()?;
         if !ecode.success() {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!(
-                    "Command {} exited with status {}",
-                    self.get_program().to_string_lossy(),
-                    ecode
-                ),
-            ));
+            return Err(io::Error::other(format!(
+                "Command {} exited with status {}",
+                self.get_program().to_string_lossy(),
+                ecode
+            )));
         }
         Ok(())
     }
+
+    fn checked_output(&mut self) -> io::Result<String>
+    {
+        debug!("Running command: {}", join_args(self, " ").to_string_lossy());
+
+        let output = self.stderr(Stdio::inherit()).output().map_err(|err| {
+            io::Error::other(format!(
+                "Failed to spawn command {}: {}",
+                self.get_program().to_string_lossy(),
+                err
+            ))
+        })?;
+
+        if !output.status.success() {
+            return Err(io::Error::other(format!(
+                "Command {} exited with status {}",
+                self.get_program().to_string_lossy(),
+                output.status
+            )));
+        }
+        String::from_utf8(output.stdout).map_err(io::Error::other)
+    }
 }
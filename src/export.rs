@@ -0,0 +1,190 @@
+//! Desktop files and icons of apps converted from distro packages
+//!
+//! Flatpak only exports desktop files and icons named after the app id, so
+//! those of the image, named after the package, are copied into the app
+//! renamed: the main desktop file and its icon to `<id>`, the others to
+//! `<id>.<name>`. `Exec=` keeps the command as seen in the sandbox, flatpak
+//! turns it into `flatpak run --command=<command> <id>` when deploying.
+
+use log::{debug, info, warn};
+use std::fs::{copy, create_dir_all, read_dir, write, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use crate::appstream::{self, DESKTOP_GROUP, ICON_THEME_DIR};
+use crate::flatpak::appname;
+use crate::Result;
+
+const PIXMAPS_DIR: &str = "usr/share/pixmaps";
+const ICON_EXTENSIONS: &[&str] = &["png", "svg", "svgz"];
+/// Keys that make no sense once renamed and sandboxed
+const DROPPED_KEYS: &[&str] = &["TryExec", "DBusActivatable"];
+
+/// Theme size directory of an icon file outside of the theme
+fn size_dir(path: &Path) -> Result<String> {
+    if path.extension().is_some_and(|e| e != "png") {
+        return Ok("scalable".to_string());
+    }
+
+    let mut header = [0; 24];
+    File::open(path)?.read_exact(&mut header)?;
+    if &header[..8] != b"\x89PNG\r\n\x1a\n" {
+        return Err(format!("{} is not a PNG image", path.display()).into());
+    }
+    let width = u32::from_be_bytes(header[16..20].try_into()?);
+    let height = u32::from_be_bytes(header[20..24].try_into()?);
+    Ok(format!("{width}x{height}"))
+}
+
+/// Icon files `icon` stands for in the image, with the theme size
+/// directory of each. Packages put icons in pixmaps too.
+fn find_icons(image: &Path, icon: &str) -> Result<Vec<(PathBuf, String)>> {
+    let mut loose = Vec::new();
+    if icon.starts_with('/') {
+        loose.push(image.join(icon.trim_start_matches('/')));
+    } else {
+        let theme = image.join(ICON_THEME_DIR);
+        let mut ret = Vec::new();
+        if theme.is_dir() {
+            for size in read_dir(&theme)? {
+                let size = size?;
+                for ext in ICON_EXTENSIONS {
+                    let path = size.path().join("apps").join(format!("{icon}.{ext}"));
+                    if path.is_file() {
+                        ret.push((path, size.file_name().to_string_lossy().into_owned()));
+                    }
+                }
+            }
+        }
+        if !ret.is_empty() {
+            ret.sort();
+            return Ok(ret);
+        }
+        for ext in ICON_EXTENSIONS {
+            loose.push(image.join(PIXMAPS_DIR).join(format!("{icon}.{ext}")));
+        }
+    }
+
+    let mut ret = Vec::new();
+    for path in loose.into_iter().filter(|p| p.is_file()) {
+        match size_dir(&path) {
+            Ok(size) => ret.push((path, size)),
+            Err(e) => warn!("Ignoring icon {}: {e}", path.display()),
+        }
+    }
+    Ok(ret)
+}
+
+/// `exec` with its command as seen in the sandbox, where the image's app
+/// `subtree`, if any, is mounted on `/app`
+fn sandbox_exec(exec: &str, subtree: Option<&Path>) -> String {
+    let (command, args, quoted) = match exec.strip_prefix('"').and_then(|e| e.split_once('"')) {
+        Some((command, args)) => (command, args, true),
+        None => match exec.split_once(' ') {
+            Some((command, args)) => (command, args, false),
+            None => (exec, "", false),
+        },
+    };
+
+    let command = match subtree.and_then(|s| Path::new(command).strip_prefix(s).ok()) {
+        Some(rel) => Path::new("/app").join(rel).to_string_lossy().into_owned(),
+        None => command.to_string(),
+    };
+
+    match (quoted, args.is_empty()) {
+        (true, _) => format!("\"{command}\"{args}"),
+        (false, true) => command,
+        (false, false) => format!("{command} {args}"),
+    }
+}
+
+/// Copy the icons named `icon` in the image to the app's theme and export
+/// trees at `dir`, as `name`. Whether any was found.
+fn export_icon(image: &Path, dir: &Path, icon: &str, name: &str) -> Result<bool> {
+    let icons = find_icons(image, icon)?;
+    for (src, size) in icons.iter() {
+        let ext = src.extension().unwrap_or_default().to_string_lossy();
+        for tree in ["files", "export"] {
+            let dest = dir.join(tree).join("share/icons/hicolor").join(size).join("apps");
+            create_dir_all(&dest)?;
+            copy(src, dest.join(format!("{name}.{ext}")))?;
+        }
+    }
+    Ok(!icons.is_empty())
+}
+
+/// Export the desktop files of the image checked out at `image`, and their
+/// icons, renamed after app `id` into the app build directory `dir`. Apps
+/// exporting desktop files of their own are left alone. Returns the
+/// (original, new) names of the desktop files.
+pub fn export_app(image: &Path, dir: &Path, id: &str, subtree: Option<&Path>) -> Result<Vec<(String, String)>> {
+    let exported = dir.join("export/share/applications");
+    if exported.is_dir() && read_dir(&exported)?.next().is_some() {
+        debug!("{id} already exports desktop files");
+        return Ok(Vec::new());
+    }
+
+    let desktops = appstream::desktop_files(image)?;
+    if desktops.is_empty() {
+        warn!("No desktop file in image, {id} won't show up in menus");
+        return Ok(Vec::new());
+    }
+
+    // the one named after the app, org.gnome.gedit.desktop for gedit
+    let name = appname(id).unwrap_or(id).to_lowercase();
+    let main = desktops
+        .iter()
+        .position(|(file, _)| {
+            let stem = file.trim_end_matches(".desktop").to_lowercase();
+            stem == name || stem.ends_with(&format!(".{name}"))
+        })
+        .unwrap_or(0);
+    let rename = |i: usize, name: &str| {
+        if name.starts_with(id) {
+            name.to_string()
+        } else if i == main {
+            id.to_string()
+        } else {
+            format!("{id}.{name}")
+        }
+    };
+
+    let mut ret = Vec::new();
+    for (i, (file, entry)) in desktops.iter().enumerate() {
+        let new_file = format!("{}.desktop", rename(i, file.trim_end_matches(".desktop")));
+        info!("Exporting {file} as {new_file}");
+
+        let mut entry = entry.clone();
+        for group in entry.groups() {
+            if let Some(exec) = entry.get(&group, "Exec") {
+                let exec = sandbox_exec(exec, subtree);
+                entry.set(&group, "Exec", &exec);
+            }
+            for key in DROPPED_KEYS {
+                entry.remove(&group, key);
+            }
+        }
+        if new_file != *file {
+            entry.set(DESKTOP_GROUP, "X-Flatpak-RenamedFrom", &format!("{file};"));
+        }
+
+        if let Some(icon) = entry.get(DESKTOP_GROUP, "Icon").map(str::to_string) {
+            let stem = Path::new(&icon).file_stem().unwrap_or_default().to_string_lossy();
+            let new_icon = rename(i, &stem);
+            if export_icon(image, dir, &icon, &new_icon)? {
+                entry.set(DESKTOP_GROUP, "Icon", &new_icon);
+            } else {
+                warn!("No {icon} icon in image, keeping it as is");
+            }
+        }
+
+        for tree in ["files", "export"] {
+            let dest = dir.join(tree).join("share/applications");
+            create_dir_all(&dest)?;
+            write(dest.join(&new_file), entry.to_string())?;
+        }
+        ret.push((file.clone(), new_file));
+    }
+
+    Ok(ret)
+}
@@ -1,15 +1,63 @@
-use indoc::formatdoc;
+use std::collections::HashMap;
 use std::ffi::OsString;
-use std::fs::{create_dir_all, write, read_to_string};
-use std::path::Path;
+use std::fs::{create_dir_all, rename, write, read_to_string, File};
+use std::io::BufWriter;
+use std::path::{Component, Path, PathBuf};
 use std::process::Command;
+use std::str::FromStr;
 
-use log::info;
+use log::{info, warn};
 use tempfile::TempDir;
 
 use crate::Result;
 use crate::exec::{CheckedRun, ostree};
 
+mod metadata;
+pub use metadata::*;
+mod permissions;
+pub use permissions::*;
+use crate::appstream;
+use crate::export;
+use crate::oci;
+use crate::rootfs;
+use crate::sanitize;
+use crate::symlinks;
+use crate::template;
+use crate::triggers::Trigger;
+
+/// Id of the runtime built for an application
+pub fn runtime_id(appname: &str) -> String {
+    format!("org.openSUSE.Platform.{appname}")
+}
+
+/// Id of the SDK matching an application's runtime
+pub fn sdk_id(appname: &str) -> String {
+    format!("org.openSUSE.Sdk.{appname}")
+}
+
+/// Id of the application built from an image
+pub fn app_id(appname: &str) -> String {
+    format!("org.openSUSE.App.{appname}")
+}
+
+/// Application name an app id was made of
+pub fn appname(app_id: &str) -> Option<&str> {
+    app_id.strip_prefix("org.openSUSE.App.")
+}
+
+/// Fail unless flatpak `installation` can run `arch`
+pub fn check_arch(installation: fn() -> Command, arch: &str) -> Result<()> {
+    let supported = installation().arg("--supported-arches").checked_output()?;
+    if !supported.lines().any(|a| a.trim() == arch) {
+        return Err(format!(
+            "Image is built for {arch}, flatpak supports {}",
+            supported.split_whitespace().collect::<Vec<_>>().join(", ")
+        )
+        .into());
+    }
+    Ok(())
+}
+
 /// Ensure publishing repo is there
 pub fn ensure_repo<P: AsRef<Path>>(repo_dir: P) -> Result<()> {
     if repo_dir.as_ref().exists() {
@@ -29,13 +77,281 @@ pub fn ensure_repo<P: AsRef<Path>>(repo_dir: P) -> Result<()> {
     Ok(())
 }
 
+/// Regenerate summary and appstream branch of the publishing repo
+pub fn update_repo<P: AsRef<Path>>(repo_dir: P, signing: Option<&Signing>) -> Result<()> {
+    Command::new("flatpak")
+        .arg("build-update-repo")
+        .args(signing.map(Signing::args).unwrap_or_default())
+        .arg(repo_dir.as_ref().as_os_str())
+        .checked_run()?;
+    Ok(())
+}
+
+/// All refs of a repo
+pub fn repo_refs(repo_dir: &Path) -> Result<Vec<String>> {
+    let refs = ostree()
+        .arg("refs")
+        .arg("--repo")
+        .arg(repo_dir.as_os_str())
+        .checked_output()?;
+    Ok(refs.lines().map(str::to_string).collect())
+}
+
+/// Commit `branch` of a repo points to, if it exists
+fn rev_parse(repo_dir: &Path, branch: &str) -> Result<Option<String>> {
+    if !repo_refs(repo_dir)?.iter().any(|r| r == branch) {
+        return Ok(None);
+    }
+
+    let rev = ostree()
+        .arg("rev-parse")
+        .arg("--repo")
+        .arg(repo_dir.as_os_str())
+        .arg(branch)
+        .checked_output()?;
+    Ok(Some(rev.trim().to_string()))
+}
+
+/// Generate the static delta from `previous` to the commit `branch` now
+/// points to, and delete deltas of the branch beyond the `max` latest.
+///
+/// Commits of a branch don't record their parents, so its history is
+/// recovered by chaining the `FROM-TO` deltas from its current commit.
+fn update_deltas(repo_dir: &Path, branch: &str, previous: Option<&str>, max: usize) -> Result<()> {
+    let current = rev_parse(repo_dir, branch)?.ok_or(format!("{branch} missing after publishing"))?;
+    let previous = match previous {
+        Some(previous) if previous != current => previous,
+        _ => return Ok(()),
+    };
+
+    info!("Generating static delta for {branch}");
+    ostree()
+        .args(["static-delta", "generate", "--repo"])
+        .arg(repo_dir.as_os_str())
+        .arg(format!("--from={previous}"))
+        .arg(format!("--to={current}"))
+        .checked_run()?;
+
+    let deltas = ostree()
+        .args(["static-delta", "list", "--repo"])
+        .arg(repo_dir.as_os_str())
+        .checked_output()?;
+    let deltas: Vec<(&str, &str)> = deltas
+        .lines()
+        .filter_map(|d| d.trim().split_once('-'))
+        .collect();
+
+    let mut to = current.as_str();
+    // bounded, in case a branch went back to an earlier commit
+    for kept in 0..deltas.len() {
+        let from = match deltas.iter().find(|(_, t)| *t == to) {
+            Some((from, _)) => *from,
+            None => break,
+        };
+        if kept >= max {
+            info!("Deleting static delta {from}-{to}");
+            ostree()
+                .args(["static-delta", "delete", "--repo"])
+                .arg(repo_dir.as_os_str())
+                .arg(format!("{from}-{to}"))
+                .checked_run()?;
+        }
+        to = from;
+    }
+
+    Ok(())
+}
+
+/// Image directories making up the runtime, merged in order. `/usr/etc`
+/// comes with `/usr`, so `/etc` overrides openSUSE's vendor defaults.
+pub const DEFAULT_SUBTREES: &[&str] = &["/usr", "/etc:etc"];
+
+/// Top level directories that are symlinks into `/usr` on usrmerged systems
+const USRMERGE: &[(&str, &str)] = &[
+    ("bin", "usr/bin"),
+    ("sbin", "usr/sbin"),
+    ("lib", "usr/lib"),
+    ("lib64", "usr/lib64"),
+];
+
+/// Part of the image that goes into the runtime: `src` in the image is
+/// placed at `dest` inside `files/`. Written as `SRC[:DEST]`.
+#[derive(Clone, Debug)]
+pub struct Subtree {
+    pub src: PathBuf,
+    pub dest: PathBuf,
+}
+
+impl FromStr for Subtree {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Subtree, String> {
+        let (src, dest) = s.split_once(':').unwrap_or((s, ""));
+        if !src.starts_with('/') {
+            return Err(format!("Subtree source must be absolute: {src}"));
+        }
+        let dest = Path::new(dest);
+        if dest.is_absolute() || dest.components().any(|c| c.as_os_str() == "..") {
+            return Err(format!("Subtree destination must stay inside files/: {}", dest.display()));
+        }
+        Ok(Subtree {
+            src: PathBuf::from(src),
+            dest: dest.to_path_buf(),
+        })
+    }
+}
+
+/// Path relative to the root, dropping `/` and `.`
+fn root_relative(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|c| matches!(c, Component::Normal(_)))
+        .collect()
+}
+
+impl Subtree {
+    /// Where image path `image` ends up, relative to the runtime's `files/`,
+    /// if it's part of the runtime at all. Usrmerge aliases like `/bin` are
+    /// treated as their `/usr` counterpart unless they're subtrees of their
+    /// own.
+    pub fn locate(subtrees: &[Subtree], image: &Path) -> Option<PathBuf> {
+        let image = root_relative(image);
+        let image = USRMERGE
+            .iter()
+            .find_map(|(alias, real)| {
+                let rest = image.strip_prefix(alias).ok()?;
+                match subtrees.iter().any(|s| root_relative(&s.src) == Path::new(alias)) {
+                    true => None,
+                    false => Some(Path::new(real).join(rest)),
+                }
+            })
+            .unwrap_or(image);
+
+        subtrees
+            .iter()
+            .map(|s| (root_relative(&s.src), &s.dest))
+            .filter(|(src, _)| image.starts_with(src))
+            .max_by_key(|(src, _)| src.components().count())
+            .map(|(src, dest)| dest.join(image.strip_prefix(&src).unwrap()))
+    }
+
+    /// Image path, relative to the image root, of a path in `files/`
+    pub fn origin(subtrees: &[Subtree], files: &Path) -> Option<PathBuf> {
+        subtrees
+            .iter()
+            .filter(|s| files.starts_with(&s.dest))
+            .max_by_key(|s| s.dest.components().count())
+            .map(|s| root_relative(&s.src).join(files.strip_prefix(&s.dest).unwrap()))
+    }
+}
+
+/// Platform and SDK runtimes built as a pair, referring to each other
+#[derive(Clone, Debug)]
+pub struct RuntimePair {
+    pub platform: String,
+    pub sdk: String,
+}
+
+/// Per build settings for runtimes
+#[derive(Default)]
+pub struct RuntimeOptions {
+    pub sanitize: sanitize::Policy,
+    /// Where to write the sanitization report as JSON
+    pub sanitize_report: Option<PathBuf>,
+    pub subtrees: Vec<Subtree>,
+    /// Caches to regenerate in the runtime tree
+    pub triggers: Vec<Trigger>,
+    /// Content of the `[Environment]` group
+    pub environment: Vec<(String, String)>,
+    pub extensions: Vec<Extension>,
+    /// Set when building one of a platform/SDK pair
+    pub pair: Option<RuntimePair>,
+    /// Appstream description, none to leave the runtime without
+    pub appstream: Option<appstream::Info>,
+    /// Static deltas to keep for the runtime's ref, 0 to not generate any
+    pub max_deltas: usize,
+    /// Image the runtime is converted from
+    pub provenance: Option<oci::Provenance>,
+}
+
+/// Manifest templates looked for at the image root, in order
+const MANIFESTS: &[&str] = &["flatpak.yaml", "flatpak.json"];
+
+/// Branch apps are committed to
+pub const APP_BRANCH: &str = "master";
+
+/// App shipped ready-made in the image
+#[derive(Clone, Debug)]
+pub struct NativeApp {
+    /// Image directory holding what goes to `/app`
+    pub subtree: PathBuf,
+    pub command: String,
+    /// Options of `flatpak build-finish`
+    pub finish_args: Vec<String>,
+}
+
+/// Per build settings for apps
+#[derive(Default)]
+pub struct AppOptions {
+    /// Build from a subtree of the image rather than with flatpak-builder
+    pub native: Option<NativeApp>,
+    /// Whether to generate appstream data
+    pub appstream: bool,
+    /// Merged into the app metadata, in order
+    pub permissions: Vec<Permission>,
+    /// Image labels, available to the manifest template
+    pub labels: HashMap<String, String>,
+    /// SDK runtime built with the runtime, available to the manifest template
+    pub sdk: Option<String>,
+    /// Image the app is converted from
+    pub provenance: Option<oci::Provenance>,
+}
+
+/// GPG key signing commits and the repo summary
+#[derive(Clone, Debug)]
+pub struct Signing {
+    pub key: String,
+    pub homedir: Option<PathBuf>,
+}
+
+impl Signing {
+    /// Options of ostree and flatpak commands to sign with the key
+    pub fn args(&self) -> Vec<OsString> {
+        let mut ret = vec![OsString::from(format!("--gpg-sign={}", self.key))];
+        if let Some(homedir) = &self.homedir {
+            let mut arg = OsString::from("--gpg-homedir=");
+            arg.push(homedir);
+            ret.push(arg);
+        }
+        ret
+    }
+
+    /// Write the public key to `path`, for remotes to verify against
+    pub fn export_public_key<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let mut gpg = Command::new("gpg");
+        if let Some(homedir) = &self.homedir {
+            gpg.arg("--homedir").arg(homedir);
+        }
+        gpg.args(["--batch", "--yes", "--output"])
+            .arg(path.as_ref())
+            .args(["--export", self.key.as_str()])
+            .checked_run()?;
+
+        // gpg is happy exporting nothing
+        if std::fs::metadata(path.as_ref())?.len() == 0 {
+            return Err(format!("No public key {} to export", self.key).into());
+        }
+        Ok(())
+    }
+}
+
 pub struct Builder {
     tmpdir: TempDir,
+    signing: Option<Signing>,
 }
 
 /// Run build process
 impl Builder {
-    pub fn new() -> Result<Builder> {
+    pub fn new(signing: Option<Signing>) -> Result<Builder> {
         let tmpdir = TempDir::new_in("/var/tmp")?;
         let tmp_repo = Path::join(tmpdir.path(), "repo");
 
@@ -46,7 +362,16 @@ impl Builder {
             .current_dir(tmpdir.path())
             .checked_run()?;
 
-        Ok(Builder { tmpdir })
+        Ok(Builder { tmpdir, signing })
+    }
+
+    /// Signing options, if any
+    fn sign_args(&self) -> Vec<OsString> {
+        self.signing.as_ref().map(Signing::args).unwrap_or_default()
+    }
+
+    fn update_repo<P: AsRef<Path>>(&self, repo_dir: P) -> Result<()> {
+        update_repo(repo_dir, self.signing.as_ref())
     }
 
     pub fn build_runtime<RepoP, LayerP, I>(
@@ -56,6 +381,7 @@ impl Builder {
         id: &str,
         arch: &str,
         ver: &str,
+        opts: &RuntimeOptions,
     ) -> Result<()>
     where
         RepoP: AsRef<Path>,
@@ -64,54 +390,126 @@ impl Builder {
     {
         let tmp_repo = Path::join(self.tmpdir.path(), "repo");
 
-        let base_branch = format!("base");
+        let base_branch = format!("base/{id}");
         let runtime_branch = format!("runtime/{id}/{arch}/{ver}");
 
+        info!("Flattening layers");
+        let mut merger = rootfs::Merger::new(layers)?;
+
+        info!("Sanitizing rootfs");
+        // what stays out of the runtime is of no concern
+        let report = merger.sanitize(opts.sanitize, |p| Subtree::locate(&opts.subtrees, p).is_some())?;
+        for f in report.findings.iter() {
+            warn!("{}: {:?}, {:?}", f.path.display(), f.issue, f.action);
+        }
+        if let Some(path) = &opts.sanitize_report {
+            serde_json::to_writer_pretty(File::create(path)?, &report)?;
+        }
+        report.check()?;
+
+        for d in merger.dropped() {
+            warn!("Dropping {}: {}", d.path.display(), d.reason);
+        }
+        let rootfs = Path::join(self.tmpdir.path(), "rootfs.tar");
+        merger.write_tar(BufWriter::new(File::create(&rootfs)?))?;
+
         info!("Commiting initial build");
+        let mut tree = OsString::from("--tree=tar=");
+        tree.push(rootfs.as_os_str());
         ostree()
             .arg("commit")
             .arg("--repo")
             .arg(tmp_repo.as_os_str())
             .args(["-b", base_branch.as_str()])
-            .args(layers.into_iter().map(|l| {
-                let mut r = OsString::from("--tree=tar=");
-                r.push(l.as_ref().as_os_str());
-                r
-            }))
+            .arg(tree)
+            .arg("--tar-autocreate-parents")
             .current_dir(self.tmpdir.path())
             .checked_run()?;
 
-        let subtree = Path::join(self.tmpdir.path(), "subtree");
-        create_dir_all(subtree.as_path())?;
+        // one per runtime, the builder may produce a platform and its SDK
+        let subtree = self.tmpdir.path().join("subtree").join(id);
+        let files = Path::join(subtree.as_path(), "files");
+        create_dir_all(files.as_path())?;
 
         info!("Commiting subtree");
-        ostree()
-            .arg("checkout")
-            .arg("--repo")
-            .arg(tmp_repo.as_os_str())
-            .args(["--subpath", "/usr"])
-            .arg("-U")
-            .arg(base_branch.as_str())
-            .arg(Path::join(subtree.as_path(), "files").as_os_str())
-            .current_dir(self.tmpdir.path())
-            .checked_run()?;
+        for s in opts.subtrees.iter() {
+            if !merger.contains(&s.src) {
+                warn!("{} not found in image, skipping", s.src.display());
+                continue;
+            }
+
+            ostree()
+                .arg("checkout")
+                .arg("--repo")
+                .arg(tmp_repo.as_os_str())
+                .arg("--subpath")
+                .arg(s.src.as_os_str())
+                .args(["-U", "--union"])
+                .arg(base_branch.as_str())
+                .arg(Path::join(files.as_path(), &s.dest).as_os_str())
+                .current_dir(self.tmpdir.path())
+                .checked_run()?;
+        }
+
+        info!("Fixing up symlinks");
+        for u in symlinks::fix_symlinks(files.as_path(), &opts.subtrees)? {
+            warn!("Dangling symlink {} -> {}: {}", u.path.display(), u.target.display(), u.reason);
+        }
+
+        for t in opts.triggers.iter() {
+            t.run(files.as_path())?;
+        }
 
         // create metadata
-        let metadata = formatdoc!(
-            "\
-	    [Runtime]
-            name={id}
-            arch={arch}
-            version={ver}"
-        );
+        let mut metadata = Metadata::new();
+        metadata
+            .set("Runtime", "name", id)
+            .set("Runtime", "arch", arch)
+            .set("Runtime", "version", ver);
+        if let Some(pair) = &opts.pair {
+            metadata
+                .set("Runtime", "runtime", &format!("{}/{arch}/{ver}", pair.platform))
+                .set("Runtime", "sdk", &format!("{}/{arch}/{ver}", pair.sdk));
+        }
+        for (key, value) in opts.environment.iter() {
+            metadata.set("Environment", key, value);
+        }
+        for ext in opts.extensions.iter() {
+            metadata.add_extension(ext);
+            // flatpak needs the mount point to exist in the runtime
+            create_dir_all(files.join(ext.directory()))?;
+        }
+        let metadata = metadata.to_string();
+
+        if let Some(info) = &opts.appstream {
+            appstream::write_runtime(files.as_path(), id, info, &runtime_branch)?;
+        }
 
         write(
             Path::join(subtree.as_path(), "metadata").as_os_str(),
             metadata.as_str(),
         )?;
 
-        ostree()
-            .arg("commit")
+        self.commit(&subtree, &runtime_branch, &metadata, opts.provenance.as_ref())?;
+
+        info!("Publishing");
+        let previous = rev_parse(repo_dir.as_ref(), &runtime_branch)?;
+        self.publish(repo_dir.as_ref(), &runtime_branch)?;
+
+        if opts.max_deltas > 0 {
+            update_deltas(repo_dir.as_ref(), &runtime_branch, previous.as_deref(), opts.max_deltas)?;
+        }
+
+        self.update_repo(repo_dir)
+    }
+
+    /// Commit flatpak build directory `dir`, holding `metadata` and the
+    /// `files` and `export` trees, to `branch` of the temporary repo
+    fn commit(&self, dir: &Path, branch: &str, metadata: &str, provenance: Option<&oci::Provenance>) -> Result<()> {
+        let tmp_repo = Path::join(self.tmpdir.path(), "repo");
+
+        let mut cmd = ostree();
+        cmd.arg("commit")
             .arg("--repo")
             .arg(tmp_repo.as_os_str())
             .args([
@@ -121,80 +519,223 @@ impl Builder {
                 "--link-checkout-speedup",
             ])
             .args(["-s", "Commit"])
-            .args(["--branch", runtime_branch.as_str()])
-            .arg(subtree.as_os_str())
+            .args(["--branch", branch])
+            .arg(dir.as_os_str())
             .args([
                 "--add-metadata-string",
                 format!("xa.metadata={}", metadata).as_str(),
-            ])
+            ]);
+        for (key, value) in provenance.map(oci::Provenance::to_metadata).unwrap_or_default() {
+            cmd.arg(format!("--add-metadata-string={key}={value}"));
+        }
+        cmd.args(self.sign_args())
             .current_dir(self.tmpdir.path())
             .checked_run()?;
 
-        info!("Publishing");
+        Ok(())
+    }
+
+    /// Copy `branch` from the temporary to the publishing repo
+    fn publish(&self, repo_dir: &Path, branch: &str) -> Result<()> {
+        let tmp_repo = Path::join(self.tmpdir.path(), "repo");
+
         ostree()
             .arg("pull-local")
             .arg("--repo")
-            .arg(repo_dir.as_ref().as_os_str())
+            .arg(repo_dir.as_os_str())
             .arg(tmp_repo.as_os_str())
-            .arg(runtime_branch.as_str())
-            .current_dir(self.tmpdir.path())
-            .checked_run()?;
-
-        Command::new("flatpak")
-            .arg("build-update-repo")
-            .arg(repo_dir.as_ref().as_os_str())
+            .arg(branch)
             .current_dir(self.tmpdir.path())
             .checked_run()?;
 
         Ok(())
     }
 
-    pub fn build_app<P: AsRef<Path>>(
+    /// Build the app with flatpak-builder from the manifest template in
+    /// the image, into `commit_dir`
+    fn build_manifest(
         &self,
-        repo_dir: P,
+        commit_dir: &Path,
         id: &str,
-	runtime: &str,
-        _arch: &str,
+        runtime: &str,
+        arch: &str,
         ver: &str,
+        opts: &AppOptions,
     ) -> Result<()> {
         let tmp_repo = Path::join(self.tmpdir.path(), "repo");
 
-        let base_branch = format!("base");
-
-	let manifest = format!("{id}.yaml");
+        let base_branch = format!("base/{runtime}");
 
 	let build_dir = self.tmpdir.path().join("app");
 
-	println!("Checking out flatpak.yaml");
+	let root = ostree()
+	    .arg("ls")
+	    .arg("--repo")
+	    .arg(tmp_repo.as_os_str())
+	    .arg(base_branch.as_str())
+	    .arg("/")
+	    .checked_output()?;
+	let template = MANIFESTS
+	    .iter()
+	    .find(|m| root.lines().any(|l| l.ends_with(&format!(" /{m}"))))
+	    .ok_or("Image has no flatpak.yaml or flatpak.json")?;
+	let json = template.ends_with(".json");
+	let manifest = format!("{id}.{}", if json { "json" } else { "yaml" });
+
+	println!("Checking out {template}");
 	ostree()
 	    .arg("checkout")
 	    .arg("--repo")
 	    .arg(tmp_repo.as_os_str())
-	    .arg("--subpath=/flatpak.yaml")
+	    .arg(format!("--subpath=/{template}"))
 	    .arg("-U")
 	    .arg(base_branch.as_str())
 	    .arg(build_dir.as_os_str())
 	    .checked_run()?;
 
 	println!("Generating manifest");
-	let content = read_to_string(build_dir.join("flatpak.yaml"))?;
-
-	// XXX we should probably optimize this
-	let content = content.replace("%FLATPAK_OCI_APPID%", id);
-	let content = content.replace("%FLATPAK_OCI_RUNTIMEID%", runtime);
-	let content = content.replace("%FLATPAK_OCI_RUNTIMEVER%", ver);
+	let content = read_to_string(build_dir.join(template))?;
+	let content = template::render(&content, |name| {
+	    let value = match name {
+		"FLATPAK_OCI_APPID" => id,
+		"FLATPAK_OCI_RUNTIMEID" => runtime,
+		"FLATPAK_OCI_SDKID" => opts.sdk.as_deref()?,
+		"FLATPAK_OCI_RUNTIMEVER" | "FLATPAK_OCI_VERSION" => ver,
+		"FLATPAK_OCI_ARCH" => arch,
+		"FLATPAK_OCI_BRANCH" => APP_BRANCH,
+		_ => opts.labels.get(name.strip_prefix("FLATPAK_OCI_LABEL:")?)?,
+	    };
+	    // placeholders of a JSON manifest are necessarily inside strings
+	    Some(match json {
+		true => {
+		    // without the enclosing quotes, the escaped ones are kept
+		    let quoted = serde_json::to_string(value).ok()?;
+		    quoted[1..quoted.len() - 1].to_string()
+		}
+		false => value.to_string(),
+	    })
+	})?;
 
 	write(build_dir.join(&manifest), content)?;
 
 	println!("Building application");
 	Command::new("flatpak-builder")
-	    .arg("--repo")
-	    .arg(repo_dir.as_ref().as_os_str())
 	    .arg("build")
 	    .arg(&manifest)
 	    .current_dir(build_dir.as_path())
 	    .checked_run()?;
 
+	let build = build_dir.join("build");
+	// only what flatpak build-export would take from the build directory
+	for entry in ["files", "export", "metadata"] {
+	    if build.join(entry).exists() {
+		rename(build.join(entry), commit_dir.join(entry))?;
+	    }
+	}
+
 	Ok(())
     }
+
+    /// Build the app from a subtree of the image holding it ready-made,
+    /// into `commit_dir`
+    fn build_native(
+        &self,
+        commit_dir: &Path,
+        id: &str,
+        runtime: &str,
+        arch: &str,
+        ver: &str,
+        native: &NativeApp,
+    ) -> Result<()> {
+        let tmp_repo = Path::join(self.tmpdir.path(), "repo");
+        let base_branch = format!("base/{runtime}");
+
+        println!("Checking out {}", native.subtree.display());
+        ostree()
+            .arg("checkout")
+            .arg("--repo")
+            .arg(tmp_repo.as_os_str())
+            .arg("--subpath")
+            .arg(native.subtree.as_os_str())
+            .args(["-U", "--union"])
+            .arg(base_branch.as_str())
+            .arg(commit_dir.join("files").as_os_str())
+            .checked_run()?;
+
+        let mut metadata = Metadata::new();
+        metadata
+            .set("Application", "name", id)
+            .set("Application", "runtime", &format!("{runtime}/{arch}/{ver}"))
+            .set("Application", "command", &native.command);
+        write(commit_dir.join("metadata"), metadata.to_string())?;
+
+        // adds finish-args to the metadata and exports desktop files,
+        // icons and D-Bus services named after the app
+        println!("Finishing application");
+        Command::new("flatpak")
+            .arg("build-finish")
+            .arg(format!("--command={}", native.command))
+            .args(native.finish_args.iter())
+            .arg(commit_dir.as_os_str())
+            .checked_run()?;
+
+        Ok(())
+    }
+
+    pub fn build_app<P: AsRef<Path>>(
+        &self,
+        repo_dir: P,
+        id: &str,
+        runtime: &str,
+        arch: &str,
+        ver: &str,
+        opts: &AppOptions,
+    ) -> Result<()> {
+        let tmp_repo = Path::join(self.tmpdir.path(), "repo");
+        let base_branch = format!("base/{runtime}");
+        let app_branch = format!("app/{id}/{arch}/{APP_BRANCH}");
+
+        let commit_dir = self.tmpdir.path().join("commit").join(id);
+        create_dir_all(&commit_dir)?;
+        match &opts.native {
+            Some(native) => self.build_native(&commit_dir, id, runtime, arch, ver, native)?,
+            None => self.build_manifest(&commit_dir, id, runtime, arch, ver, opts)?,
+        }
+
+        if !opts.permissions.is_empty() {
+            let path = commit_dir.join("metadata");
+            let mut metadata = Metadata::parse(&read_to_string(&path)?)?;
+            for p in opts.permissions.iter() {
+                p.apply(&mut metadata);
+            }
+            write(&path, metadata.to_string())?;
+        }
+
+        let image = self.tmpdir.path().join("image").join(id);
+        for dir in appstream::SOURCE_DIRS {
+            ostree()
+                .arg("checkout")
+                .arg("--repo")
+                .arg(tmp_repo.as_os_str())
+                .arg("--subpath")
+                .arg(dir)
+                .args(["-U", "--union"])
+                .arg(base_branch.as_str())
+                .arg(image.join(dir).as_os_str())
+                .checked_run()?;
+        }
+
+        let subtree = opts.native.as_ref().map(|n| n.subtree.as_path());
+        let renamed = export::export_app(image.as_path(), &commit_dir, id, subtree)?;
+        if opts.appstream {
+            appstream::write_app(image.as_path(), commit_dir.join("files").as_path(), id, &app_branch, &renamed)?;
+        }
+
+        println!("Exporting application");
+        let metadata = read_to_string(commit_dir.join("metadata"))?;
+        self.commit(&commit_dir, &app_branch, &metadata, opts.provenance.as_ref())?;
+        self.publish(repo_dir.as_ref(), &app_branch)?;
+
+        self.update_repo(repo_dir)
+    }
 }
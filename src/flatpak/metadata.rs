@@ -0,0 +1,216 @@
+use std::fmt;
+
+use crate::Result;
+
+/// Keys flatpak understands in `[Extension ...]` groups
+const EXTENSION_KEYS: &[&str] = &[
+    "add-ld-path",
+    "autodelete",
+    "directory",
+    "download-if",
+    "enable-if",
+    "locale-subset",
+    "merge-dirs",
+    "no-autodownload",
+    "subdirectories",
+    "subdirectory-suffix",
+    "version",
+    "versions",
+];
+const EXTENSION_BOOL_KEYS: &[&str] = &["autodelete", "locale-subset", "no-autodownload", "subdirectories"];
+
+/// Flatpak metadata keyfile, keeping groups and keys in insertion order
+#[derive(Clone, Debug, Default)]
+pub struct Metadata {
+    groups: Vec<(String, Vec<(String, String)>)>,
+}
+
+/// Escape a value the way GKeyFile expects it
+fn escape(value: &str) -> String {
+    let mut ret = String::with_capacity(value.len());
+    for (i, c) in value.chars().enumerate() {
+        match c {
+            ' ' if i == 0 => ret.push_str("\\s"),
+            '\\' => ret.push_str("\\\\"),
+            '\n' => ret.push_str("\\n"),
+            '\t' => ret.push_str("\\t"),
+            '\r' => ret.push_str("\\r"),
+            c => ret.push(c),
+        }
+    }
+    ret
+}
+
+fn unescape(value: &str) -> String {
+    let mut ret = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            ret.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('s') => ret.push(' '),
+            Some('n') => ret.push('\n'),
+            Some('t') => ret.push('\t'),
+            Some('r') => ret.push('\r'),
+            Some(c) => ret.push(c),
+            None => ret.push('\\'),
+        }
+    }
+    ret
+}
+
+/// Extension point of a runtime
+#[derive(Clone, Debug)]
+pub struct Extension {
+    pub name: String,
+    pub keys: Vec<(String, String)>,
+}
+
+impl Extension {
+    pub fn new(name: &str, keys: Vec<(String, String)>) -> Result<Extension> {
+        for (key, value) in keys.iter() {
+            if !EXTENSION_KEYS.contains(&key.as_str()) {
+                return Err(format!("Unknown key {key} for extension {name}").into());
+            }
+            if EXTENSION_BOOL_KEYS.contains(&key.as_str()) && value != "true" && value != "false" {
+                return Err(format!("{key} of extension {name} must be true or false").into());
+            }
+        }
+        match keys.iter().find(|(k, _)| k == "directory") {
+            None => return Err(format!("Extension {name} lacks a directory").into()),
+            Some((_, dir)) if dir.starts_with('/') || dir.split('/').any(|c| c == "..") => {
+                return Err(format!("Directory of extension {name} must be inside the runtime").into())
+            }
+            _ => (),
+        }
+
+        Ok(Extension {
+            name: name.to_string(),
+            keys,
+        })
+    }
+
+    /// Directory, relative to the runtime's files, the extension is mounted on
+    pub fn directory(&self) -> &str {
+        self.keys
+            .iter()
+            .find(|(k, _)| k == "directory")
+            .map(|(_, v)| v.as_str())
+            .unwrap()
+    }
+
+    /// All `[Extension ...]` groups of a metadata file
+    pub fn from_metadata(metadata: &Metadata) -> Result<Vec<Extension>> {
+        metadata
+            .groups
+            .iter()
+            .filter_map(|(group, keys)| Some((group.strip_prefix("Extension ")?, keys)))
+            .map(|(name, keys)| Extension::new(name.trim(), keys.clone()))
+            .collect()
+    }
+}
+
+impl Metadata {
+    pub fn new() -> Metadata {
+        Metadata::default()
+    }
+
+    /// Parse a keyfile. Comments are dropped.
+    pub fn parse(content: &str) -> Result<Metadata> {
+        let mut ret = Metadata::new();
+        let mut group: Option<String> = None;
+
+        for (n, line) in content.lines().enumerate() {
+            let line = line.trim_start();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.trim_end().strip_suffix(']')) {
+                if !ret.groups.iter().any(|(g, _)| g == name) {
+                    ret.groups.push((name.to_string(), Vec::new()));
+                }
+                group = Some(name.to_string());
+                continue;
+            }
+
+            let (key, value) = line
+                .split_once('=')
+                .ok_or(format!("Line {}: expected key=value", n + 1))?;
+            let group = group
+                .as_deref()
+                .ok_or(format!("Line {}: key outside of a group", n + 1))?;
+            ret.set(group, key.trim_end(), &unescape(value.trim_start()));
+        }
+
+        Ok(ret)
+    }
+
+    /// Set `key` in `group`, creating the group if needed
+    pub fn set(&mut self, group: &str, key: &str, value: &str) -> &mut Metadata {
+        let idx = match self.groups.iter().position(|(g, _)| g == group) {
+            Some(idx) => idx,
+            None => {
+                self.groups.push((group.to_string(), Vec::new()));
+                self.groups.len() - 1
+            }
+        };
+
+        let entries = &mut self.groups[idx].1;
+        match entries.iter_mut().find(|(k, _)| k == key) {
+            Some(entry) => entry.1 = value.to_string(),
+            None => entries.push((key.to_string(), value.to_string())),
+        }
+        self
+    }
+
+    /// Value of `key` in `group`
+    pub fn get(&self, group: &str, key: &str) -> Option<&str> {
+        self.groups
+            .iter()
+            .find(|(g, _)| g == group)?
+            .1
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Remove `key` from `group`, if there
+    pub fn remove(&mut self, group: &str, key: &str) -> &mut Metadata {
+        if let Some((_, entries)) = self.groups.iter_mut().find(|(g, _)| g == group) {
+            entries.retain(|(k, _)| k != key);
+        }
+        self
+    }
+
+    /// Names of the groups, in order
+    pub fn groups(&self) -> Vec<String> {
+        self.groups.iter().map(|(g, _)| g.clone()).collect()
+    }
+
+    /// Add an `[Extension ...]` group
+    pub fn add_extension(&mut self, ext: &Extension) -> &mut Metadata {
+        let group = format!("Extension {}", ext.name);
+        for (key, value) in ext.keys.iter() {
+            self.set(&group, key, value);
+        }
+        self
+    }
+}
+
+impl fmt::Display for Metadata {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (group, entries)) in self.groups.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            writeln!(f, "[{group}]")?;
+            for (key, value) in entries {
+                writeln!(f, "{key}={}", escape(value))?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,212 @@
+use std::fs::read_to_string;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::str::FromStr;
+
+use super::Metadata;
+use crate::Result;
+
+/// `flatpak build-finish` options a permission may be
+const OPTIONS: &[&str] = &[
+    "share",
+    "unshare",
+    "socket",
+    "nosocket",
+    "device",
+    "nodevice",
+    "allow",
+    "disallow",
+    "filesystem",
+    "nofilesystem",
+    "persist",
+    "env",
+    "talk-name",
+    "own-name",
+    "no-talk-name",
+    "system-talk-name",
+    "system-own-name",
+    "system-no-talk-name",
+];
+
+/// Sandbox permission, a `flatpak build-finish` option like
+/// `socket=wayland`, the leading dashes being optional
+#[derive(Clone, Debug, PartialEq)]
+pub struct Permission {
+    option: String,
+    value: String,
+}
+
+impl FromStr for Permission {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let s = s.trim();
+        let s = s.strip_prefix("--").unwrap_or(s);
+        let (option, value) = s
+            .split_once('=')
+            .ok_or(format!("Permission {s} lacks a value"))?;
+        if !OPTIONS.contains(&option) {
+            return Err(format!("Unknown permission {option}"));
+        }
+        if value.is_empty() || (option == "env" && !value.contains('=')) {
+            return Err(format!("Invalid value for permission {option}: {value}"));
+        }
+
+        Ok(Permission {
+            option: option.to_string(),
+            value: value.to_string(),
+        })
+    }
+}
+
+/// What a `[Context]` list entry is about, without its denial or, for
+/// filesystems, access mode
+fn list_item_name<'a>(key: &str, item: &'a str) -> &'a str {
+    let item = item.trim_start_matches('!');
+    match item.rsplit_once(':') {
+        Some((name, "ro" | "rw" | "create")) if key == "filesystems" => name,
+        _ => item,
+    }
+}
+
+/// Add `item` to the `;` terminated list `key` of `[Context]`, replacing
+/// whatever it said about the same thing
+fn add_to_list(metadata: &mut Metadata, key: &str, item: &str) {
+    let name = list_item_name(key, item);
+    let mut items: Vec<&str> = metadata
+        .get("Context", key)
+        .unwrap_or_default()
+        .split(';')
+        .filter(|i| !i.is_empty() && list_item_name(key, i) != name)
+        .collect();
+    items.push(item);
+    let value = format!("{};", items.join(";"));
+    metadata.set("Context", key, &value);
+}
+
+impl Permission {
+    /// Merge into app `metadata`, overriding what it says about the same
+    /// socket, device, path, bus name or variable
+    pub fn apply(&self, metadata: &mut Metadata) {
+        let value = self.value.as_str();
+        let denied = format!("!{value}");
+        match self.option.as_str() {
+            "share" => add_to_list(metadata, "shared", value),
+            "unshare" => add_to_list(metadata, "shared", &denied),
+            "socket" => add_to_list(metadata, "sockets", value),
+            "nosocket" => add_to_list(metadata, "sockets", &denied),
+            "device" => add_to_list(metadata, "devices", value),
+            "nodevice" => add_to_list(metadata, "devices", &denied),
+            "allow" => add_to_list(metadata, "features", value),
+            "disallow" => add_to_list(metadata, "features", &denied),
+            "filesystem" => add_to_list(metadata, "filesystems", value),
+            "nofilesystem" => add_to_list(metadata, "filesystems", &denied),
+            "persist" => add_to_list(metadata, "persistent", value),
+            "env" => {
+                let (var, value) = value.split_once('=').unwrap();
+                metadata.set("Environment", var, value);
+            }
+            "talk-name" => {
+                metadata.set("Session Bus Policy", value, "talk");
+            }
+            "own-name" => {
+                metadata.set("Session Bus Policy", value, "own");
+            }
+            "no-talk-name" => {
+                metadata.set("Session Bus Policy", value, "none");
+            }
+            "system-talk-name" => {
+                metadata.set("System Bus Policy", value, "talk");
+            }
+            "system-own-name" => {
+                metadata.set("System Bus Policy", value, "own");
+            }
+            "system-no-talk-name" => {
+                metadata.set("System Bus Policy", value, "none");
+            }
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// Permissions of an override file, one per line, `#` starting comments.
+/// A missing file has none.
+pub fn read_permissions(path: &Path) -> Result<Vec<Permission>> {
+    let content = match read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    content
+        .lines()
+        .enumerate()
+        .map(|(n, l)| (n, l.split('#').next().unwrap_or_default().trim()))
+        .filter(|(_, l)| !l.is_empty())
+        .map(|(n, l)| {
+            l.parse()
+                .map_err(|e| format!("{}:{}: {e}", path.display(), n + 1).into())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::write;
+    use tempfile::TempDir;
+
+    fn apply(metadata: &str, permission: &str) -> Metadata {
+        let mut metadata = Metadata::parse(metadata).unwrap();
+        permission.parse::<Permission>().unwrap().apply(&mut metadata);
+        metadata
+    }
+
+    #[test]
+    fn nofilesystem_overrides_access_mode() {
+        let metadata = apply("[Context]\nfilesystems=home:ro;xdg-download;\n", "nofilesystem=home");
+        assert_eq!(metadata.get("Context", "filesystems"), Some("xdg-download;!home;"));
+    }
+
+    #[test]
+    fn nosocket_replaces_list_entry() {
+        let metadata = apply("[Context]\nsockets=x11;wayland;\n", "--nosocket=x11");
+        assert_eq!(metadata.get("Context", "sockets"), Some("wayland;!x11;"));
+
+        let metadata = apply("[Context]\nsockets=!x11;\n", "socket=x11");
+        assert_eq!(metadata.get("Context", "sockets"), Some("x11;"));
+    }
+
+    #[test]
+    fn no_talk_name_overrides_bus_policy() {
+        let metadata = apply(
+            "[Session Bus Policy]\norg.freedesktop.Notifications=talk\n",
+            "no-talk-name=org.freedesktop.Notifications",
+        );
+        assert_eq!(metadata.get("Session Bus Policy", "org.freedesktop.Notifications"), Some("none"));
+    }
+
+    #[test]
+    fn invalid_permissions_are_rejected() {
+        for s in ["socket", "socket=", "env=FOO", "filesystems=home"] {
+            assert!(s.parse::<Permission>().is_err(), "{s}");
+        }
+    }
+
+    #[test]
+    fn override_file_errors_name_the_line() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("org.openSUSE.App.gedit");
+        assert!(read_permissions(&path).unwrap().is_empty());
+
+        write(&path, "# restricted\nnosocket=x11 # no X\n\nsocket\n").unwrap();
+        let err = read_permissions(&path).unwrap_err();
+        assert_eq!(err.to_string(), format!("{}:4: Permission socket lacks a value", path.display()));
+
+        write(&path, "nosocket=x11 # no X\n--device=dri\n").unwrap();
+        assert_eq!(
+            read_permissions(&path).unwrap(),
+            vec!["nosocket=x11".parse().unwrap(), "device=dri".parse().unwrap()]
+        );
+    }
+}
@@ -2,12 +2,21 @@ use clap::{Parser, Subcommand};
 use std::error::Error;
 use std::sync::OnceLock;
 
+mod appstream;
 mod commands;
+mod compress;
 mod download;
+mod environment;
 mod exec;
+mod export;
 mod flatpak;
 mod obs;
 mod oci;
+mod rootfs;
+mod sanitize;
+mod symlinks;
+mod template;
+mod triggers;
 
 type Result<T> = std::result::Result<T, Box<dyn Error>>;
 
@@ -27,6 +36,11 @@ enum Commands {
     ObsFetch(commands::obs_fetch::Args),
     Pull(commands::pull::Args),
     Install(commands::install::Args),
+    Bundle(commands::bundle::Args),
+    Info(commands::info::Args),
+    Update(commands::update::Args),
+    Uninstall(commands::uninstall::Args),
+    List(commands::list::Args),
 }
 
 fn main() -> Result<()> {
@@ -41,5 +55,10 @@ fn main() -> Result<()> {
         Commands::ObsFetch(args) => commands::obs_fetch::run(args),
         Commands::Pull(args) => commands::pull::run(args),
 	Commands::Install(args) => commands::install::run(args),
+	Commands::Bundle(args) => commands::bundle::run(args),
+	Commands::Info(args) => commands::info::run(args),
+	Commands::Update(args) => commands::update::run(args),
+	Commands::Uninstall(args) => commands::uninstall::run(args),
+	Commands::List(args) => commands::list::run(args),
     }
 }
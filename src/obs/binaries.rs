@@ -16,18 +16,12 @@ struct ObsBinaryList {
 struct ObsBinary {
     #[serde(rename = "@filename")]
     filename: String,
-    #[serde(rename = "@size")]
-    size: u64,
-    #[serde(rename = "@mtime")]
-    mtime: u64,
 }
 
 // Binary file handler
 #[derive(Debug)]
 pub struct Binary {
     pub filename: String,
-    pub size: u64,
-    pub mtime: u64,
 
     pub project: String,
     pub repository: String,
@@ -50,8 +44,6 @@ impl ObsApi {
             .into_iter()
             .map(|i| Binary {
                 filename: i.filename,
-                size: i.size,
-                mtime: i.mtime,
 
                 project: proj.to_string(),
                 repository: repo.to_string(),
@@ -82,7 +74,7 @@ where {
 
         let output = output_name.unwrap_or(&bin.filename);
         let file_path = dir_path
-            .and_then(|p| Some(p.as_ref().join(output)))
+            .map(|p| p.as_ref().join(output))
             .unwrap_or_else(|| PathBuf::from(output));
 
 	download::run(file_path, res)?;
@@ -1,26 +1,50 @@
 use log::{debug, info};
 use serde::Deserialize;
 use std::fs::File;
-use std::io;
+use std::io::{self, Read};
 use std::path::Path;
 use tar::Archive;
 
+use crate::compress;
+
 pub struct ContainerImage {
-    ar: Archive<File>,
-    manifest: Option<Manifest>,
+    ar: Archive<Box<dyn Read>>,
+    manifests: Vec<Manifest>,
+    selected: Option<usize>,
 }
 
 #[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
 struct Manifest {
-    #[serde(rename = "Layers")]
+    config: String,
+    // `docker save` writes `null` for untagged images
+    #[serde(default)]
+    repo_tags: Option<Vec<String>>,
     layers: Vec<String>,
 }
 
+impl Manifest {
+    fn tags(&self) -> &[String] {
+        self.repo_tags.as_deref().unwrap_or_default()
+    }
+
+    /// Whether `tag` names this image. A tag without explicit version
+    /// matches `:latest`, as docker does.
+    fn matches(&self, tag: &str) -> bool {
+        self.tags().iter().any(|t| {
+            t == tag || (!tag.contains(':') && *t == format!("{tag}:latest"))
+        })
+    }
+}
+
 impl ContainerImage {
     pub fn new<P: AsRef<Path>>(path: P) -> io::Result<ContainerImage> {
-        let file = File::open(path)?;
-        let ar = Archive::new(file);
-        Ok(ContainerImage { ar, manifest: None })
+        let ar = Archive::new(compress::open(path)?);
+        Ok(ContainerImage {
+            ar,
+            manifests: Vec::new(),
+            selected: None,
+        })
     }
 
     /// Unpack FS Layers into specified location
@@ -32,18 +56,78 @@ impl ContainerImage {
         self.ar.unpack(extract_path.as_ref())?;
 
         debug!("Reading manifest");
-        let mut vm: Vec<Manifest> =
+        let vm: Vec<Manifest> =
             serde_json::from_reader(File::open(Path::join(extract_path.as_ref(), "manifest.json"))?)?;
-        if vm.len() != 1 {
-            panic!("Empty manifest or multiple elements");
+        if vm.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "manifest.json doesn't list any image",
+            ));
         }
 
-        self.manifest = Some(vm.pop().unwrap());
+        self.manifests = vm;
 
         Ok(())
     }
 
+    /// Select the image to work on, either by one of its repo tags or by
+    /// its index in `manifest.json`. Without a selector the archive must
+    /// hold exactly one image.
+    pub fn select(&mut self, image: Option<&str>) -> io::Result<()> {
+        let idx = match image {
+            None if self.manifests.len() == 1 => 0,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "Archive contains {} images, select one with --image: {}",
+                        self.manifests.len(),
+                        self.describe()
+                    ),
+                ))
+            }
+            Some(sel) => self
+                .manifests
+                .iter()
+                .position(|m| m.matches(sel))
+                .or_else(|| sel.parse().ok().filter(|i| *i < self.manifests.len()))
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("No image {sel} in archive: {}", self.describe()),
+                    )
+                })?,
+        };
+
+        debug!("Selected image #{idx} ({})", self.manifests[idx].config);
+        self.selected = Some(idx);
+
+        Ok(())
+    }
+
+    /// Human readable list of images in the archive
+    fn describe(&self) -> String {
+        self.manifests
+            .iter()
+            .enumerate()
+            .map(|(i, m)| match m.tags() {
+                [] => format!("#{i} <untagged>"),
+                tags => format!("#{i} {}", tags.join(" ")),
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    fn manifest(&self) -> Option<&Manifest> {
+        self.selected.map(|i| &self.manifests[i])
+    }
+
     pub fn layers(&self) -> Option<&Vec<String>> {
-	self.manifest.as_ref().map(|m| &m.layers)
+	self.manifest().map(|m| &m.layers)
+    }
+
+    /// Path of the image config JSON inside the archive
+    pub fn config(&self) -> Option<&str> {
+        self.manifest().map(|m| m.config.as_str())
     }
 }
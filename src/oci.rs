@@ -1,11 +1,23 @@
 use reqwest::blocking::Client;
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer, Serialize};
 use std::collections::HashMap;
 use std::fmt::Debug;
 use std::path::Path;
 
 use crate::{download, Result};
 
+pub const LABEL_APPNAME: &str = "org.opensuse.flatpak.appname";
+pub const LABEL_VERSION: &str = "org.opencontainers.image.version";
+pub const LABEL_TITLE: &str = "org.opencontainers.image.title";
+pub const LABEL_DESCRIPTION: &str = "org.opencontainers.image.description";
+pub const LABEL_SUBTREES: &str = "org.opensuse.flatpak.subtrees";
+pub const LABEL_APP_SUBTREE: &str = "org.opensuse.flatpak.app-subtree";
+pub const LABEL_COMMAND: &str = "org.opensuse.flatpak.command";
+/// Comma separated sandbox permissions, like `socket=wayland,device=dri`
+pub const LABEL_PERMISSIONS: &str = "org.opensuse.flatpak.permissions";
+/// Prefix of `<prefix><extension name>.<key>` labels declaring extensions
+pub const LABEL_EXTENSION_PREFIX: &str = "org.opensuse.flatpak.extension.";
+
 pub struct Api {
     base: String,
     client: reqwest::blocking::Client,
@@ -14,34 +26,175 @@ pub struct Api {
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct Blob {
-    pub media_type: String,
-    pub size: u64,
     pub digest: String,
 }
 
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct Manifest {
-    pub schema_version: u64,
-    pub media_type: String,
     pub config: Blob,
     pub layers: Vec<Blob>,
+    /// Digest of the manifest itself, as served by the registry
+    #[serde(skip)]
+    pub digest: String,
 }
 
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "PascalCase")]
 pub struct ConfigField {
+    #[serde(default, deserialize_with = "nullable")]
     pub labels: HashMap<String, String>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub env: Vec<String>,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct Config {
-    pub author: String,
+    pub created: Option<String>,
     pub architecture: String,
-    pub os: String,
+    /// CPU variant, e.g. `v7` for `arm`
+    pub variant: Option<String>,
     pub config: ConfigField,
 }
 
+/// Docker writes `null` for empty collections, treat it as default
+fn nullable<'de, D, T>(d: D) -> std::result::Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de> + Default,
+{
+    Ok(Option::<T>::deserialize(d)?.unwrap_or_default())
+}
+
+impl Config {
+    pub fn label(&self, name: &str) -> Result<&str> {
+        Ok(self
+            .config
+            .labels
+            .get(name)
+            .ok_or(format!("Missing label: {name}"))?)
+    }
+
+    /// Flatpak architecture name of the image
+    pub fn flatpak_arch(&self) -> Result<&'static str> {
+        match (self.architecture.as_str(), self.variant.as_deref()) {
+            ("amd64", _) => Ok("x86_64"),
+            ("arm64", None | Some("v8")) => Ok("aarch64"),
+            // docker assumes v7 for arm images without variant
+            ("arm", None | Some("v7")) => Ok("arm"),
+            ("386", _) => Ok("i386"),
+            ("ppc64le", _) => Ok("ppc64le"),
+            ("s390x", _) => Ok("s390x"),
+            ("riscv64", _) => Ok("riscv64"),
+            ("loong64", _) => Ok("loongarch64"),
+            (arch, Some(variant)) => Err(format!("Unsupported architecture: {arch}/{variant}").into()),
+            (arch, None) => Err(format!("Unsupported architecture: {arch}").into()),
+        }
+    }
+}
+
+/// Image in a registry
+#[derive(Clone, Debug)]
+pub struct Reference {
+    pub registry: String,
+    pub repository: String,
+    pub tag: String,
+}
+
+/// Commit metadata keys recording where a ref was converted from
+const PROVENANCE_REGISTRY: &str = "oci.registry";
+const PROVENANCE_REPOSITORY: &str = "oci.repository";
+const PROVENANCE_TAG: &str = "oci.tag";
+const PROVENANCE_MANIFEST: &str = "oci.manifest-digest";
+const PROVENANCE_CONFIG: &str = "oci.config-digest";
+const PROVENANCE_LAYERS: &str = "oci.layer-digests";
+const PROVENANCE_CREATED: &str = "oci.created";
+
+/// Image a runtime or app was converted from
+#[derive(Clone, Debug, Serialize)]
+pub struct Provenance {
+    pub registry: String,
+    pub repository: String,
+    pub tag: String,
+    pub manifest_digest: String,
+    pub config_digest: String,
+    pub layer_digests: Vec<String>,
+    pub created: Option<String>,
+}
+
+impl Provenance {
+    pub fn new(reference: &Reference, manifest: &Manifest, config: &Config) -> Provenance {
+        Provenance {
+            registry: reference.registry.clone(),
+            repository: reference.repository.clone(),
+            tag: reference.tag.clone(),
+            manifest_digest: manifest.digest.clone(),
+            config_digest: manifest.config.digest.clone(),
+            layer_digests: manifest.layers.iter().map(|l| l.digest.clone()).collect(),
+            created: config.created.clone(),
+        }
+    }
+
+    /// Where the image can be pulled again
+    pub fn reference(&self) -> Reference {
+        Reference {
+            registry: self.registry.clone(),
+            repository: self.repository.clone(),
+            tag: self.tag.clone(),
+        }
+    }
+
+    /// Whether this is the image `reference` points to
+    pub fn is(&self, reference: &Reference) -> bool {
+        self.registry == reference.registry && self.repository == reference.repository && self.tag == reference.tag
+    }
+
+    /// Commit metadata entries, as string values
+    pub fn to_metadata(&self) -> Vec<(&'static str, String)> {
+        let mut ret = vec![
+            (PROVENANCE_REGISTRY, self.registry.clone()),
+            (PROVENANCE_REPOSITORY, self.repository.clone()),
+            (PROVENANCE_TAG, self.tag.clone()),
+            (PROVENANCE_MANIFEST, self.manifest_digest.clone()),
+            (PROVENANCE_CONFIG, self.config_digest.clone()),
+            (PROVENANCE_LAYERS, self.layer_digests.join(",")),
+        ];
+        if let Some(created) = &self.created {
+            ret.push((PROVENANCE_CREATED, created.clone()));
+        }
+        ret
+    }
+
+    /// Read back from commit metadata, `get` looking up a key. `None` for
+    /// commits that weren't converted from a registry image.
+    pub fn from_metadata<F>(get: F) -> Result<Option<Provenance>>
+    where
+        F: Fn(&str) -> Result<Option<String>>,
+    {
+        let registry = match get(PROVENANCE_REGISTRY)? {
+            Some(registry) => registry,
+            None => return Ok(None),
+        };
+        let required = |key: &str| -> Result<String> {
+            Ok(get(key)?.ok_or(format!("Commit metadata lacks {key}"))?)
+        };
+
+        Ok(Some(Provenance {
+            registry,
+            repository: required(PROVENANCE_REPOSITORY)?,
+            tag: required(PROVENANCE_TAG)?,
+            manifest_digest: required(PROVENANCE_MANIFEST)?,
+            config_digest: required(PROVENANCE_CONFIG)?,
+            layer_digests: required(PROVENANCE_LAYERS)?
+                .split(',')
+                .filter(|l| !l.is_empty())
+                .map(str::to_string)
+                .collect(),
+            created: get(PROVENANCE_CREATED)?,
+        }))
+    }
+}
+
 impl Api {
     pub fn new(base: &str) -> Result<Api> {
         let base = base.to_string();
@@ -59,7 +212,10 @@ impl Api {
             ))
             .send()?;
 
-        Ok(serde_json::from_reader(res)?)
+        let body = res.bytes()?;
+        let mut manifest: Manifest = serde_json::from_slice(&body)?;
+        manifest.digest = format!("sha256:{}", sha256::digest(&body[..]));
+        Ok(manifest)
     }
 
     pub fn get_config(&self, name: &str, cfg: &Blob) -> Result<Config> {
@@ -0,0 +1,507 @@
+//! Merging of image layers into a single root filesystem
+//!
+//! Layers are stacked as described in the OCI image spec: later layers win,
+//! `.wh.<name>` entries delete `<name>` from lower layers and
+//! `.wh..wh..opq` hides everything lower layers put into a directory.
+
+use log::debug;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Read, Write};
+use std::ops::Bound;
+use std::path::{Component, Path, PathBuf};
+use tar::{Archive, EntryType, Header};
+
+use crate::compress;
+use crate::sanitize;
+use crate::Result;
+
+const WHITEOUT_PREFIX: &str = ".wh.";
+const WHITEOUT_OPAQUE: &str = ".wh..wh..opq";
+
+/// Position of an entry: (layer, index in layer)
+type EntryId = (usize, usize);
+
+enum Kind {
+    Dir,
+    File,
+    Symlink(PathBuf),
+    /// Hardlink to the file entry holding the data
+    Hardlink(EntryId),
+    /// Something that can't go into the rootfs, with the reason why
+    Unsupported(String),
+}
+
+struct Node {
+    id: EntryId,
+    header: Header,
+    kind: Kind,
+}
+
+/// Entry left out of the merged rootfs
+#[derive(Debug)]
+pub struct Dropped {
+    pub path: PathBuf,
+    pub reason: String,
+}
+
+pub struct Merger {
+    layers: Vec<PathBuf>,
+    tree: BTreeMap<PathBuf, Node>,
+}
+
+/// Path of a layer entry relative to the image root
+fn entry_path<R: Read>(entry: &tar::Entry<R>) -> io::Result<PathBuf> {
+    normalize(&entry.path()?)
+}
+
+fn normalize(path: &Path) -> io::Result<PathBuf> {
+    let mut ret = PathBuf::new();
+    for c in path.components() {
+        match c {
+            Component::Normal(c) => ret.push(c),
+            Component::RootDir | Component::CurDir => (),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid path in layer: {}", path.display()),
+                ))
+            }
+        }
+    }
+    Ok(ret)
+}
+
+fn describe(ty: EntryType) -> String {
+    match ty {
+        EntryType::Char => "character device".to_string(),
+        EntryType::Block => "block device".to_string(),
+        EntryType::Fifo => "fifo".to_string(),
+        other => format!("unsupported entry type {:?}", other.as_byte() as char),
+    }
+}
+
+impl Merger {
+    /// Scan layers, lowest first, and work out which entry provides each
+    /// path of the merged rootfs
+    pub fn new<I, P>(layers: I) -> Result<Merger>
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<Path>,
+    {
+        let mut ret = Merger {
+            layers: layers.into_iter().map(|l| l.as_ref().to_path_buf()).collect(),
+            tree: BTreeMap::new(),
+        };
+
+        for l in 0..ret.layers.len() {
+            ret.scan_layer(l)?;
+        }
+
+        Ok(ret)
+    }
+
+    fn scan_layer(&mut self, l: usize) -> Result<()> {
+        let mut ar = Archive::new(compress::open(&self.layers[l])?);
+        for (i, entry) in ar.entries()?.enumerate() {
+            let entry = entry?;
+            let ty = entry.header().entry_type();
+            if ty.is_pax_global_extensions() {
+                continue;
+            }
+
+            let path = entry_path(&entry)?;
+            let name = match path.file_name() {
+                Some(name) => name.to_string_lossy(),
+                // the root directory itself
+                None => continue,
+            };
+            let parent = path.parent().unwrap_or(Path::new(""));
+
+            if name == WHITEOUT_OPAQUE {
+                self.remove_lower(parent, l, false);
+                continue;
+            } else if let Some(hidden) = name.strip_prefix(WHITEOUT_PREFIX) {
+                self.remove_lower(&parent.join(hidden), l, true);
+                continue;
+            }
+
+            let kind = match ty {
+                EntryType::Directory => Kind::Dir,
+                EntryType::Regular | EntryType::Continuous | EntryType::GNUSparse => Kind::File,
+                EntryType::Symlink => Kind::Symlink(
+                    entry
+                        .link_name()?
+                        .ok_or(format!("Symlink without target: {}", path.display()))?
+                        .into_owned(),
+                ),
+                EntryType::Link => {
+                    let target = normalize(
+                        &entry
+                            .link_name()?
+                            .ok_or(format!("Hardlink without target: {}", path.display()))?,
+                    )?;
+                    // Link to whatever the target is at this point, later
+                    // layers replacing the target don't affect the link
+                    match self.tree.get(&target).map(|n| &n.kind) {
+                        Some(Kind::File) => Kind::Hardlink(self.tree[&target].id),
+                        Some(Kind::Hardlink(src)) => Kind::Hardlink(*src),
+                        _ => Kind::Unsupported(format!(
+                            "hardlink to missing file {}",
+                            target.display()
+                        )),
+                    }
+                }
+                other => Kind::Unsupported(describe(other)),
+            };
+
+            // directories merge with lower ones, anything else replaces
+            // them with all their content
+            if !matches!(kind, Kind::Dir) {
+                self.remove_lower(&path, l, false);
+            }
+
+            let mut header = entry.header().clone();
+            if matches!(kind, Kind::File) {
+                header.set_entry_type(EntryType::Regular);
+                header.set_size(entry.size());
+            }
+
+            self.tree.insert(path, Node { id: (l, i), header, kind });
+        }
+
+        Ok(())
+    }
+
+    /// Drop `path` (if `inclusive`) and everything below it that came from
+    /// a layer lower than `layer`
+    fn remove_lower(&mut self, path: &Path, layer: usize, inclusive: bool) {
+        let doomed: Vec<PathBuf> = self
+            .tree
+            .range::<Path, _>((Bound::Included(path), Bound::Unbounded))
+            .take_while(|(p, _)| p.starts_with(path))
+            .filter(|(p, n)| n.id.0 < layer && (inclusive || p.as_path() != path))
+            .map(|(p, _)| p.clone())
+            .collect();
+
+        for p in doomed {
+            debug!("Whiteout {}", p.display());
+            self.tree.remove(&p);
+        }
+    }
+
+    /// Whether `path` exists in the merged rootfs, either as an entry of
+    /// its own or as parent of one
+    pub fn contains<P: AsRef<Path>>(&self, path: P) -> bool {
+        let path = match normalize(path.as_ref()) {
+            Ok(path) => path,
+            Err(_) => return false,
+        };
+        self.tree
+            .range::<Path, _>((Bound::Included(path.as_path()), Bound::Unbounded))
+            .next()
+            .is_some_and(|(p, _)| p.starts_with(&path))
+    }
+
+    /// Entries that will be left out of the rootfs
+    pub fn dropped(&self) -> Vec<Dropped> {
+        self.tree
+            .iter()
+            .filter_map(|(path, n)| match &n.kind {
+                Kind::Unsupported(reason) => Some(Dropped {
+                    path: path.clone(),
+                    reason: reason.clone(),
+                }),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Check the entries of the rootfs `shipped` says end up in the
+    /// runtime against the sanitization policy, fixing what the policy
+    /// asks for
+    pub fn sanitize<F>(&mut self, policy: sanitize::Policy, shipped: F) -> Result<sanitize::Report>
+    where
+        F: Fn(&Path) -> bool,
+    {
+        let mut report = sanitize::Report::new(policy);
+        let mut removed = Vec::new();
+
+        for (path, node) in self.tree.iter_mut().filter(|(p, _)| shipped(p)) {
+            if report.apply(path, &mut node.header)? {
+                removed.push(path.clone());
+            }
+        }
+        for path in removed {
+            self.tree.remove(&path);
+        }
+
+        Ok(report)
+    }
+
+    /// Write the merged rootfs as a tar stream.
+    ///
+    /// Directories and symlinks come first, followed by file data in layer
+    /// order and finally hardlinks, so that link targets always precede
+    /// their links.
+    pub fn write_tar<W: Write>(&self, out: W) -> Result<()> {
+        let mut builder = tar::Builder::new(out);
+
+        // Every hardlinked file needs a path holding its data. That's the
+        // original path if it survived, otherwise the first link to it.
+        let mut anchors: HashMap<EntryId, &Path> = HashMap::new();
+        for (path, node) in self.tree.iter() {
+            if let Kind::File = node.kind {
+                anchors.insert(node.id, path);
+            }
+        }
+        for (path, node) in self.tree.iter() {
+            if let Kind::Hardlink(src) = node.kind {
+                anchors.entry(src).or_insert(path);
+            }
+        }
+
+        for (path, node) in self.tree.iter() {
+            let mut header = node.header.clone();
+            match &node.kind {
+                Kind::Dir => builder.append_data(&mut header, path, io::empty())?,
+                Kind::Symlink(target) => builder.append_link(&mut header, path, target)?,
+                _ => (),
+            }
+        }
+
+        for (l, layer) in self.layers.iter().enumerate() {
+            let mut ar = Archive::new(compress::open(layer)?);
+            for (i, entry) in ar.entries()?.enumerate() {
+                let mut entry = entry?;
+                let path = match anchors.get(&(l, i)) {
+                    Some(path) => path,
+                    None => continue,
+                };
+
+                // Metadata is taken from the rootfs entry, it may have
+                // been altered since
+                let mut header = self.tree[*path].header.clone();
+                header.set_entry_type(EntryType::Regular);
+                header.set_size(entry.size());
+                builder.append_data(&mut header, path, &mut entry)?;
+            }
+        }
+
+        for (path, node) in self.tree.iter() {
+            if let Kind::Hardlink(src) = node.kind {
+                let anchor = anchors[&src];
+                if anchor != path {
+                    let mut header = node.header.clone();
+                    builder.append_link(&mut header, path, anchor)?;
+                }
+            }
+        }
+
+        builder.into_inner()?.flush()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::write;
+    use tempfile::TempDir;
+
+    enum Entry<'a> {
+        Dir(&'a str),
+        File(&'a str, &'a str),
+        /// Empty file with the given mode
+        Mode(&'a str, u32),
+        Hardlink(&'a str, &'a str),
+        Special(&'a str, EntryType),
+    }
+    use Entry::*;
+
+    /// Layer tar holding `entries`, in memory
+    fn layer(entries: &[Entry]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for e in entries {
+            let mut header = Header::new_gnu();
+            header.set_mode(0o644);
+            header.set_size(0);
+            match e {
+                Dir(path) => {
+                    header.set_entry_type(EntryType::Directory);
+                    header.set_mode(0o755);
+                    builder.append_data(&mut header, path, io::empty()).unwrap();
+                }
+                File(path, data) => {
+                    header.set_entry_type(EntryType::Regular);
+                    header.set_size(data.len() as u64);
+                    builder.append_data(&mut header, path, data.as_bytes()).unwrap();
+                }
+                Mode(path, mode) => {
+                    header.set_entry_type(EntryType::Regular);
+                    header.set_mode(*mode);
+                    builder.append_data(&mut header, path, io::empty()).unwrap();
+                }
+                Hardlink(path, target) => {
+                    header.set_entry_type(EntryType::Link);
+                    builder.append_link(&mut header, path, target).unwrap();
+                }
+                Special(path, ty) => {
+                    header.set_entry_type(*ty);
+                    builder.append_data(&mut header, path, io::empty()).unwrap();
+                }
+            }
+        }
+        builder.into_inner().unwrap()
+    }
+
+    /// Merge `layers`, lowest first. The layer files live as long as the
+    /// returned directory.
+    fn merge(layers: &[Vec<u8>]) -> (TempDir, Merger) {
+        let dir = TempDir::new().unwrap();
+        let mut paths = Vec::new();
+        for (i, l) in layers.iter().enumerate() {
+            let path = dir.path().join(format!("layer{i}.tar"));
+            write(&path, l).unwrap();
+            paths.push(path);
+        }
+        let merger = Merger::new(paths).unwrap();
+        (dir, merger)
+    }
+
+    /// Sorted `(path, what)` of the rootfs written by `merger`
+    fn rootfs(merger: &Merger) -> Vec<(String, String)> {
+        let mut out = Vec::new();
+        merger.write_tar(&mut out).unwrap();
+
+        let mut ar = Archive::new(out.as_slice());
+        let mut ret: Vec<(String, String)> = ar
+            .entries()
+            .unwrap()
+            .map(|e| {
+                let mut e = e.unwrap();
+                let path = e.path().unwrap().to_string_lossy().trim_end_matches('/').to_string();
+                let what = match e.header().entry_type() {
+                    EntryType::Directory => "dir".to_string(),
+                    EntryType::Regular => {
+                        let mut data = String::new();
+                        e.read_to_string(&mut data).unwrap();
+                        format!("file {data}")
+                    }
+                    EntryType::Link => format!("hardlink {}", e.link_name().unwrap().unwrap().display()),
+                    other => format!("{other:?}"),
+                };
+                (path, what)
+            })
+            .collect();
+        ret.sort();
+        ret
+    }
+
+    fn expected(entries: &[(&str, &str)]) -> Vec<(String, String)> {
+        entries.iter().map(|(p, w)| (p.to_string(), w.to_string())).collect()
+    }
+
+    #[test]
+    fn whiteout_removes_lower_entry() {
+        let (_dir, merger) = merge(&[
+            layer(&[Dir("a"), File("a/b", "b"), File("a/c", "c")]),
+            layer(&[File("a/.wh.b", "")]),
+        ]);
+        assert_eq!(rootfs(&merger), expected(&[("a", "dir"), ("a/c", "file c")]));
+    }
+
+    #[test]
+    fn whiteout_keeps_same_layer_entry() {
+        let (_dir, merger) = merge(&[
+            layer(&[Dir("a"), File("a/b", "old")]),
+            layer(&[File("a/.wh.b", ""), File("a/b", "new")]),
+        ]);
+        assert_eq!(rootfs(&merger), expected(&[("a", "dir"), ("a/b", "file new")]));
+    }
+
+    #[test]
+    fn opaque_dir_hides_lower_content() {
+        let (_dir, merger) = merge(&[
+            layer(&[Dir("d"), File("d/x", "x"), Dir("d/sub"), File("d/sub/y", "y")]),
+            layer(&[Dir("d"), File("d/.wh..wh..opq", ""), File("d/z", "z")]),
+        ]);
+        assert_eq!(rootfs(&merger), expected(&[("d", "dir"), ("d/z", "file z")]));
+    }
+
+    #[test]
+    fn file_replaces_dir() {
+        let (_dir, merger) = merge(&[
+            layer(&[Dir("d"), File("d/x", "x"), Dir("d/sub")]),
+            layer(&[File("d", "now a file")]),
+        ]);
+        assert_eq!(rootfs(&merger), expected(&[("d", "file now a file")]));
+    }
+
+    #[test]
+    fn hardlink_survives_whiteout_of_target() {
+        let (_dir, merger) = merge(&[
+            layer(&[File("f", "data"), Hardlink("g", "f"), Hardlink("h", "f")]),
+            layer(&[File(".wh.f", "")]),
+        ]);
+        // the first remaining link holds the data, the others link to it
+        assert_eq!(
+            rootfs(&merger),
+            expected(&[("g", "file data"), ("h", "hardlink g")])
+        );
+    }
+
+    #[test]
+    fn hardlink_keeps_replaced_target_data() {
+        let (_dir, merger) = merge(&[
+            layer(&[File("f", "old"), Hardlink("g", "f")]),
+            layer(&[File("f", "new")]),
+        ]);
+        assert_eq!(
+            rootfs(&merger),
+            expected(&[("f", "file new"), ("g", "file old")])
+        );
+    }
+
+    #[test]
+    fn special_files_are_dropped() {
+        let (_dir, merger) = merge(&[layer(&[
+            Dir("dev"),
+            Special("dev/fifo", EntryType::Fifo),
+            Special("dev/null", EntryType::Char),
+            Special("dev/sda", EntryType::Block),
+            File("keep", "k"),
+        ])]);
+
+        let dropped: Vec<(String, String)> = merger
+            .dropped()
+            .into_iter()
+            .map(|d| (d.path.display().to_string(), d.reason))
+            .collect();
+        assert_eq!(
+            dropped,
+            expected(&[
+                ("dev/fifo", "fifo"),
+                ("dev/null", "character device"),
+                ("dev/sda", "block device"),
+            ])
+        );
+        assert_eq!(rootfs(&merger), expected(&[("dev", "dir"), ("keep", "file k")]));
+    }
+
+    #[test]
+    fn sanitize_only_checks_shipped_entries() {
+        let (_dir, mut merger) = merge(&[layer(&[
+            Dir("usr"),
+            Mode("usr/su", 0o4755),
+            Dir("root"),
+            Mode("root/su", 0o4755),
+        ])]);
+
+        let report = merger
+            .sanitize(sanitize::Policy::Fail, |p| p.starts_with("usr"))
+            .unwrap();
+        let findings: Vec<&Path> = report.findings.iter().map(|f| f.path.as_path()).collect();
+        assert_eq!(findings, [Path::new("usr/su")]);
+    }
+}
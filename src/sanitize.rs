@@ -0,0 +1,140 @@
+//! Checks for content flatpak runtimes must not ship
+
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use tar::{EntryType, Header};
+
+use crate::Result;
+
+const S_ISUID: u32 = 0o4000;
+const S_ISGID: u32 = 0o2000;
+const S_ISVTX: u32 = 0o1000;
+const S_IWOTH: u32 = 0o0002;
+
+/// What to do about offending entries
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Policy {
+    /// Report only. Device nodes are still removed, ostree can't store them.
+    Warn,
+    /// Clear offending mode bits and remove device nodes
+    #[default]
+    Strip,
+    /// Refuse to build the runtime
+    Fail,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Issue {
+    Setuid,
+    Setgid,
+    Device,
+    WorldWritable,
+}
+
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Kept,
+    Stripped,
+    Removed,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Finding {
+    pub path: PathBuf,
+    pub issue: Issue,
+    pub action: Action,
+    /// Mode before and after, in octal
+    pub mode: String,
+    pub new_mode: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub policy: Policy,
+    pub findings: Vec<Finding>,
+}
+
+/// Problems with a single entry
+pub fn inspect(header: &Header) -> Result<Vec<Issue>> {
+    let ty = header.entry_type();
+    if ty == EntryType::Char || ty == EntryType::Block {
+        return Ok(vec![Issue::Device]);
+    }
+
+    let mode = header.mode()?;
+    let mut ret = Vec::new();
+    if mode & S_ISUID != 0 {
+        ret.push(Issue::Setuid);
+    }
+    if mode & S_ISGID != 0 && !ty.is_dir() {
+        ret.push(Issue::Setgid);
+    }
+    if mode & S_IWOTH != 0 && mode & S_ISVTX == 0 && !ty.is_symlink() {
+        ret.push(Issue::WorldWritable);
+    }
+    Ok(ret)
+}
+
+/// Mode bits that fix an issue
+fn fixed_mode(mode: u32, issue: Issue) -> u32 {
+    match issue {
+        Issue::Setuid => mode & !S_ISUID,
+        Issue::Setgid => mode & !S_ISGID,
+        Issue::WorldWritable => mode & !S_IWOTH,
+        Issue::Device => mode,
+    }
+}
+
+impl Report {
+    pub fn new(policy: Policy) -> Report {
+        Report {
+            policy,
+            findings: Vec::new(),
+        }
+    }
+
+    /// Record the issues of an entry and, depending on the policy, fix
+    /// its header. Returns whether the entry has to be removed.
+    pub fn apply(&mut self, path: &Path, header: &mut Header) -> Result<bool> {
+        let mut remove = false;
+        for issue in inspect(header)? {
+            let mode = header.mode()?;
+            let (action, new_mode) = match (issue, self.policy) {
+                (Issue::Device, _) => {
+                    remove = true;
+                    (Action::Removed, None)
+                }
+                (_, Policy::Strip) => {
+                    let new_mode = fixed_mode(mode, issue);
+                    header.set_mode(new_mode);
+                    (Action::Stripped, Some(format!("{new_mode:04o}")))
+                }
+                _ => (Action::Kept, None),
+            };
+
+            self.findings.push(Finding {
+                path: path.to_path_buf(),
+                issue,
+                action,
+                mode: format!("{mode:04o}"),
+                new_mode,
+            });
+        }
+        Ok(remove)
+    }
+
+    /// Fail if the policy doesn't allow any of the findings
+    pub fn check(&self) -> Result<()> {
+        if self.policy == Policy::Fail && !self.findings.is_empty() {
+            return Err(format!(
+                "Sanitization failed, {} offending entries in image",
+                self.findings.len()
+            )
+            .into());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,287 @@
+//! Fixing up symlinks that point outside of a runtime tree
+//!
+//! A runtime only carries some subtrees of the image, and ends up mounted
+//! at `/usr` in the sandbox. Absolute symlinks, or relative ones not
+//! pointing where their target landed (climbing out of the tree, or out of
+//! a subtree placed elsewhere), are rewritten to relative links to wherever
+//! their target landed in the runtime. Symlink chains leaving the tree (e.g.
+//! through `/etc/alternatives`) are followed down to the concrete file.
+
+use log::info;
+use std::ffi::OsString;
+use std::fs;
+use std::os::unix::fs::symlink;
+use std::path::{Component, Path, PathBuf};
+
+use crate::flatpak::Subtree;
+use crate::Result;
+
+const MAX_HOPS: usize = 40;
+
+/// Symlink that couldn't be fixed
+#[derive(Debug)]
+pub struct Unfixable {
+    pub path: PathBuf,
+    pub target: PathBuf,
+    pub reason: String,
+}
+
+/// Resolve `target` against `base` without touching the filesystem. All
+/// paths are relative to a root, `None` if `target` climbs above it.
+fn lexical(base: &Path, target: &Path) -> Option<PathBuf> {
+    let mut ret = if target.is_absolute() {
+        PathBuf::new()
+    } else {
+        base.to_path_buf()
+    };
+    for c in target.components() {
+        match c {
+            Component::Normal(c) => ret.push(c),
+            Component::ParentDir if !ret.pop() => return None,
+            _ => (),
+        }
+    }
+    Some(ret)
+}
+
+/// Relative path from directory `from` to `to`
+fn relative_to(from: &Path, to: &Path) -> PathBuf {
+    let common = from
+        .components()
+        .zip(to.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let mut ret = PathBuf::new();
+    for _ in common..from.components().count() {
+        ret.push("..");
+    }
+    for c in to.components().skip(common) {
+        ret.push(c);
+    }
+    if ret.as_os_str().is_empty() {
+        ret.push(".");
+    }
+    ret
+}
+
+/// Names and `..` making up `path`, last one first
+fn names(path: &Path) -> Vec<OsString> {
+    path.components()
+        .rev()
+        .filter_map(|c| match c {
+            Component::Normal(c) => Some(c.to_os_string()),
+            Component::ParentDir => Some(OsString::from("..")),
+            _ => None,
+        })
+        .collect()
+}
+
+struct Fixer<'a> {
+    files: &'a Path,
+    subtrees: &'a [Subtree],
+}
+
+impl Fixer<'_> {
+    /// Whether symlink `link` to `target`, relative to the runtime root,
+    /// already points where its target landed in the runtime
+    fn points_right(&self, link: &Path, target: &Path) -> bool {
+        let parent = link.parent().unwrap_or(Path::new(""));
+        if target.is_absolute() {
+            return false;
+        }
+        let image = Subtree::origin(self.subtrees, parent).and_then(|p| lexical(&p, target));
+        match (image, lexical(parent, target)) {
+            (Some(image), Some(rel)) => Subtree::locate(self.subtrees, &image) == Some(rel),
+            _ => false,
+        }
+    }
+
+    /// Follow an image path until it lands on something inside the runtime.
+    /// The path is walked one component at a time inside the tree, symlinks
+    /// not rewritten yet would otherwise be followed on the host.
+    fn resolve(&self, image: PathBuf) -> std::result::Result<PathBuf, String> {
+        // names left to walk, next one last
+        let mut todo = names(&image);
+        // image path walked so far, free of symlinks
+        let mut done = PathBuf::new();
+        let mut hops = 0;
+
+        while let Some(name) = todo.pop() {
+            if name == ".." {
+                if !done.pop() {
+                    return Err(format!("/{} points above /", image.display()));
+                }
+                continue;
+            }
+            let next = done.join(name);
+            let rel = match Subtree::locate(self.subtrees, &next) {
+                Some(rel) => rel,
+                // a parent of a subtree, nothing to follow in the tree
+                None => {
+                    done = next;
+                    continue;
+                }
+            };
+            let path = self.files.join(&rel);
+            let meta = fs::symlink_metadata(&path)
+                .map_err(|_| format!("/{} doesn't exist in the runtime", next.display()))?;
+            if !meta.file_type().is_symlink() {
+                done = next;
+                continue;
+            }
+
+            hops += 1;
+            if hops > MAX_HOPS {
+                return Err("Too many levels of symbolic links".to_string());
+            }
+            let target = fs::read_link(&path).map_err(|e| e.to_string())?;
+            if todo.is_empty() && self.points_right(&rel, &target) {
+                // the rest of the chain stays inside the runtime
+                return Ok(rel);
+            }
+            if target.is_absolute() {
+                done = PathBuf::new();
+            }
+            todo.extend(names(&target));
+        }
+
+        Subtree::locate(self.subtrees, &done).ok_or(format!("/{} is not part of the runtime", done.display()))
+    }
+
+    /// Collect all symlinks below `dir`, relative to the runtime root
+    fn walk(&self, dir: &Path, out: &mut Vec<PathBuf>) -> Result<()> {
+        for entry in fs::read_dir(self.files.join(dir))? {
+            let entry = entry?;
+            let rel = dir.join(entry.file_name());
+            let ty = entry.file_type()?;
+            if ty.is_symlink() {
+                out.push(rel);
+            } else if ty.is_dir() {
+                self.walk(&rel, out)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn fix(&self, link: &Path) -> Result<Option<Unfixable>> {
+        let abs = self.files.join(link);
+        let target = fs::read_link(&abs)?;
+        let parent = link.parent().unwrap_or(Path::new(""));
+
+        if self.points_right(link, &target) {
+            return Ok(None);
+        }
+
+        let image = Subtree::origin(self.subtrees, parent).and_then(|p| lexical(&p, &target));
+        let resolved = match image {
+            Some(image) => self.resolve(image),
+            None => Err(format!("{} points above /", target.display())),
+        };
+
+        match resolved {
+            Ok(rel) => {
+                let new_target = relative_to(parent, &rel);
+                info!("Rewriting symlink {}: {} -> {}", link.display(), target.display(), new_target.display());
+                fs::remove_file(&abs)?;
+                symlink(new_target, &abs)?;
+                Ok(None)
+            }
+            Err(reason) => Ok(Some(Unfixable {
+                path: link.to_path_buf(),
+                target,
+                reason,
+            })),
+        }
+    }
+}
+
+/// Rewrite symlinks in the runtime tree `files` whose targets escape it.
+/// `subtrees` tell where the image directories are placed inside `files`.
+/// Returns the symlinks that couldn't be fixed.
+pub fn fix_symlinks(files: &Path, subtrees: &[Subtree]) -> Result<Vec<Unfixable>> {
+    let fixer = Fixer { files, subtrees };
+
+    let mut links = Vec::new();
+    fixer.walk(Path::new(""), &mut links)?;
+    links.sort();
+
+    let mut ret = Vec::new();
+    for link in links {
+        if let Some(u) = fixer.fix(&link)? {
+            ret.push(u);
+        }
+    }
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, write};
+    use tempfile::TempDir;
+
+    fn subtrees(specs: &[&str]) -> Vec<Subtree> {
+        specs.iter().map(|s| s.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn absolute_symlink_becomes_relative() {
+        let dir = TempDir::new().unwrap();
+        let files = dir.path();
+        create_dir_all(files.join("bin")).unwrap();
+        create_dir_all(files.join("lib")).unwrap();
+        write(files.join("lib/tool"), "").unwrap();
+        symlink("/usr/lib/tool", files.join("bin/tool")).unwrap();
+
+        let unfixable = fix_symlinks(files, &subtrees(&["/usr"])).unwrap();
+        assert!(unfixable.is_empty());
+        assert_eq!(fs::read_link(files.join("bin/tool")).unwrap(), Path::new("../lib/tool"));
+    }
+
+    #[test]
+    fn relative_symlink_out_of_a_moved_subtree_is_rewritten() {
+        // ../../usr/lib/x stays inside files/, but /usr is placed at its root
+        let dir = TempDir::new().unwrap();
+        let files = dir.path();
+        create_dir_all(files.join("opt/foo")).unwrap();
+        create_dir_all(files.join("lib")).unwrap();
+        write(files.join("lib/x"), "").unwrap();
+        symlink("../../usr/lib/x", files.join("opt/foo/x")).unwrap();
+
+        let unfixable = fix_symlinks(files, &subtrees(&["/usr", "/opt/foo:opt/foo"])).unwrap();
+        assert!(unfixable.is_empty(), "{unfixable:?}");
+        assert_eq!(fs::read_link(files.join("opt/foo/x")).unwrap(), Path::new("../../lib/x"));
+    }
+
+    #[test]
+    fn intermediate_symlinks_resolve_inside_the_tree() {
+        // /usr/lib/alt -> /usr/real exists on no host, following it with
+        // the kernel fails
+        let dir = TempDir::new().unwrap();
+        let files = dir.path();
+        create_dir_all(files.join("real")).unwrap();
+        create_dir_all(files.join("lib")).unwrap();
+        create_dir_all(files.join("bin")).unwrap();
+        write(files.join("real/x"), "").unwrap();
+        symlink("/usr/real", files.join("lib/alt")).unwrap();
+        symlink("/usr/lib/alt/x", files.join("bin/x")).unwrap();
+
+        let unfixable = fix_symlinks(files, &subtrees(&["/usr"])).unwrap();
+        assert!(unfixable.is_empty(), "{unfixable:?}");
+        assert_eq!(fs::read_link(files.join("bin/x")).unwrap(), Path::new("../real/x"));
+        assert_eq!(fs::read_link(files.join("lib/alt")).unwrap(), Path::new("../real"));
+    }
+
+    #[test]
+    fn symlink_out_of_the_runtime_is_unfixable() {
+        let dir = TempDir::new().unwrap();
+        let files = dir.path();
+        create_dir_all(files.join("bin")).unwrap();
+        symlink("/var/lib/thing", files.join("bin/thing")).unwrap();
+
+        let unfixable = fix_symlinks(files, &subtrees(&["/usr"])).unwrap();
+        assert_eq!(unfixable.len(), 1);
+        assert_eq!(unfixable[0].path, Path::new("bin/thing"));
+    }
+}
@@ -0,0 +1,107 @@
+//! `%FLATPAK_OCI_NAME%` placeholders in app manifests
+//!
+//! A placeholder is a name starting with `FLATPAK_OCI_`, made of letters,
+//! digits and `_.:-`, between two `%`. Anything else is kept as is, so
+//! text like `100% free` or `date +%Y%m%d` passes through. `%%FLATPAK_OCI_`
+//! is a literal `%FLATPAK_OCI_`.
+
+use crate::Result;
+
+const PREFIX: &str = "FLATPAK_OCI_";
+
+fn is_name(s: &str) -> bool {
+    s.starts_with(PREFIX)
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | ':' | '-'))
+}
+
+/// Replace all placeholders in `template` with what `lookup` returns for
+/// their name. Fails on names `lookup` doesn't know.
+pub fn render<F>(template: &str, lookup: F) -> Result<String>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut ret = String::with_capacity(template.len());
+    for (n, line) in template.split_inclusive('\n').enumerate() {
+        let mut rest = line;
+        while let Some(start) = rest.find('%') {
+            ret.push_str(&rest[..start]);
+            let after = &rest[start + 1..];
+
+            if let Some(after) = after.strip_prefix('%').filter(|a| a.starts_with(PREFIX)) {
+                ret.push('%');
+                ret.push_str(PREFIX);
+                rest = &after[PREFIX.len()..];
+                continue;
+            }
+
+            match after.find('%').map(|end| (&after[..end], &after[end + 1..])) {
+                Some((name, after)) if is_name(name) => {
+                    let value = lookup(name)
+                        .ok_or(format!("Line {}: undefined placeholder %{name}%", n + 1))?;
+                    ret.push_str(&value);
+                    rest = after;
+                }
+                _ => {
+                    ret.push('%');
+                    rest = after;
+                }
+            }
+        }
+        ret.push_str(rest);
+    }
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lookup(name: &str) -> Option<String> {
+        match name {
+            "FLATPAK_OCI_APPID" => Some("org.openSUSE.App.gedit".to_string()),
+            "FLATPAK_OCI_LABEL:org.opencontainers.image.version" => Some("46.2".to_string()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn replaces_placeholders() {
+        assert_eq!(
+            render("app-id: %FLATPAK_OCI_APPID%\n", lookup).unwrap(),
+            "app-id: org.openSUSE.App.gedit\n"
+        );
+        assert_eq!(
+            render("v%FLATPAK_OCI_LABEL:org.opencontainers.image.version%-%FLATPAK_OCI_APPID%", lookup).unwrap(),
+            "v46.2-org.openSUSE.App.gedit"
+        );
+    }
+
+    #[test]
+    fn undefined_placeholder_fails() {
+        let err = render("a\nb: %FLATPAK_OCI_NOPE%\n", lookup).unwrap_err();
+        assert_eq!(err.to_string(), "Line 2: undefined placeholder %FLATPAK_OCI_NOPE%");
+    }
+
+    #[test]
+    fn other_percent_signs_pass_through() {
+        for text in [
+            "date +%Y%m%d",
+            "100% free",
+            "echo ${name%%.*} %%",
+            "%FLATPAK_OCI_ not closed",
+            "%FLATPAK_OCI_A B%",
+            "trailing %",
+        ] {
+            assert_eq!(render(text, lookup).unwrap(), text);
+        }
+    }
+
+    #[test]
+    fn escaped_placeholder_is_literal() {
+        assert_eq!(
+            render("%%FLATPAK_OCI_APPID% is %FLATPAK_OCI_APPID%", lookup).unwrap(),
+            "%FLATPAK_OCI_APPID% is org.openSUSE.App.gedit"
+        );
+    }
+}
@@ -0,0 +1,122 @@
+//! Cache regeneration for runtime trees
+//!
+//! RPM scriptlets updated caches for the image layout, which don't survive
+//! the conversion. They are regenerated with the runtime's own tools, run
+//! in a sandbox where the tree is mounted at `/usr` like flatpak does.
+
+use log::{debug, info};
+use std::fs::{create_dir_all, write};
+use std::path::{Path, PathBuf};
+
+use crate::exec::{bwrap, CheckedRun};
+use crate::Result;
+
+const LIB_DIRS: &[&str] = &["lib64", "lib"];
+
+/// fontconfig snippet making it read the cache written to the runtime
+const FONTCONFIG_CACHEDIR: &str = "etc/fonts/conf.d/05-flatpak-oci-tools-cache.conf";
+const FONTCONFIG_CACHEDIR_CONF: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE fontconfig SYSTEM "urn:fontconfig:fonts.dtd">
+<fontconfig>
+  <cachedir>/usr/cache/fontconfig</cachedir>
+</fontconfig>
+"#;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Trigger {
+    /// ld.so.cache, written to /usr/etc/ld.so.cache
+    Ldconfig,
+    /// Font cache, written to /usr/cache/fontconfig, which is added to the
+    /// cache directories through /usr/etc/fonts/conf.d
+    Fontconfig,
+    /// GIO module caches
+    GioModules,
+    /// GDK pixbuf loader caches
+    GdkPixbuf,
+}
+
+impl Trigger {
+    pub const ALL: &'static [Trigger] = &[
+        Trigger::Ldconfig,
+        Trigger::Fontconfig,
+        Trigger::GioModules,
+        Trigger::GdkPixbuf,
+    ];
+
+    /// First of `candidates` present in the runtime, as seen in the sandbox
+    fn find(files: &Path, candidates: &[&str]) -> Option<PathBuf> {
+        candidates
+            .iter()
+            .find(|c| files.join(c).is_file())
+            .map(|c| Path::new("/usr").join(c))
+    }
+
+    /// Library directories of the runtime containing `subdir`
+    fn lib_dirs(files: &Path, subdir: &str) -> Vec<PathBuf> {
+        LIB_DIRS
+            .iter()
+            .filter(|l| files.join(l).join(subdir).is_dir())
+            .map(|l| Path::new("/usr").join(l).join(subdir))
+            .collect()
+    }
+
+    /// Run the trigger on runtime tree `files`. Triggers whose tools aren't
+    /// part of the runtime are skipped.
+    pub fn run(&self, files: &Path) -> Result<()> {
+        let program = match self {
+            Trigger::Ldconfig => Self::find(files, &["sbin/ldconfig", "bin/ldconfig"]),
+            Trigger::Fontconfig => Self::find(files, &["bin/fc-cache"]),
+            Trigger::GioModules => Self::find(files, &["bin/gio-querymodules-64", "bin/gio-querymodules"]),
+            Trigger::GdkPixbuf => Self::find(
+                files,
+                &["bin/gdk-pixbuf-query-loaders-64", "bin/gdk-pixbuf-query-loaders"],
+            ),
+        };
+        let program = match program {
+            Some(program) => program,
+            None => {
+                debug!("No tools for trigger {self:?} in runtime, skipping");
+                return Ok(());
+            }
+        };
+
+        info!("Running trigger {self:?}");
+        match self {
+            Trigger::Ldconfig => bwrap(files)
+                .arg(program)
+                .args(["-X", "-C", "/usr/etc/ld.so.cache", "-f", "/usr/etc/ld.so.conf"])
+                .checked_run()?,
+            Trigger::Fontconfig => {
+                // without config listing it, fontconfig would never look
+                // at the cache
+                let conf_d = files.join(FONTCONFIG_CACHEDIR);
+                if !conf_d.parent().is_some_and(Path::is_dir) {
+                    debug!("No fontconfig configuration in runtime, skipping font cache");
+                    return Ok(());
+                }
+                write(conf_d, FONTCONFIG_CACHEDIR_CONF)?;
+
+                let cache = files.join("cache/fontconfig");
+                create_dir_all(&cache)?;
+                bwrap(files)
+                    .arg("--bind")
+                    .arg(&cache)
+                    .arg("/var/cache/fontconfig")
+                    .arg(program)
+                    .args(["--force", "--system-only"])
+                    .checked_run()?
+            }
+            Trigger::GioModules => {
+                for dir in Self::lib_dirs(files, "gio/modules") {
+                    bwrap(files).arg(&program).arg(dir).checked_run()?;
+                }
+            }
+            Trigger::GdkPixbuf => bwrap(files)
+                .arg(program)
+                .arg("--update-cache")
+                .checked_run()?,
+        }
+
+        Ok(())
+    }
+}